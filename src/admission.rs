@@ -0,0 +1,126 @@
+use std::{collections::HashSet, num::ParseIntError, path::PathBuf, time::SystemTime};
+
+use eyre::eyre;
+use tracing::{info, warn};
+
+/// Parse a device address, either decimal or `0x` prefixed hexadecimal.
+pub fn parse_addr(value: &str) -> Result<u32, ParseIntError> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+}
+
+/// Devices allowed to join the mesh.
+///
+/// Denied addresses always win, and an empty allow list lets every other
+/// device in.
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: HashSet<u32>,
+    deny: HashSet<u32>,
+}
+
+impl AccessList {
+    pub fn new(allow: impl IntoIterator<Item = u32>, deny: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            allow: allow.into_iter().collect(),
+            deny: deny.into_iter().collect(),
+        }
+    }
+
+    pub fn is_allowed(&self, addr: u32) -> bool {
+        !self.deny.contains(&addr) && (self.allow.is_empty() || self.allow.contains(&addr))
+    }
+
+    /// Parse an access list file, made of `allow <addr>` and `deny <addr>` lines.
+    ///
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(content: &str) -> crate::Result<Self> {
+        let mut list = Self::default();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (rule, addr) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| eyre!("line {}: expected `<allow|deny> <addr>`", index + 1))?;
+            let addr = parse_addr(addr.trim())
+                .map_err(|err| eyre!("line {}: invalid address: {err}", index + 1))?;
+            match rule {
+                "allow" => list.allow.insert(addr),
+                "deny" => list.deny.insert(addr),
+                _ => return Err(eyre!("line {}: unknown rule `{rule}`", index + 1)),
+            };
+        }
+        Ok(list)
+    }
+
+    fn extend(&mut self, other: &Self) {
+        self.allow.extend(&other.allow);
+        self.deny.extend(&other.deny);
+    }
+}
+
+/// Access list from the command line, merged with a file reloaded when it changes.
+pub struct Admission {
+    base: AccessList,
+    current: AccessList,
+    file: Option<PathBuf>,
+    modified: Option<SystemTime>,
+}
+
+impl Admission {
+    pub fn new(base: AccessList, file: Option<PathBuf>) -> Self {
+        let mut admission = Self {
+            current: base.clone(),
+            base,
+            file,
+            modified: None,
+        };
+        admission.reload();
+        admission
+    }
+
+    pub fn is_allowed(&self, addr: u32) -> bool {
+        self.current.is_allowed(addr)
+    }
+
+    /// Reload the access list file if it changed since the last call.
+    ///
+    /// A file that cannot be read or parsed keeps the previous rules.
+    pub fn reload(&mut self) {
+        let Some(file) = &self.file else {
+            return;
+        };
+
+        let modified = std::fs::metadata(file).and_then(|metadata| metadata.modified());
+        let modified = match modified {
+            Ok(modified) if Some(modified) == self.modified => return,
+            Ok(modified) => modified,
+            Err(err) => {
+                warn!(file = %file.display(), %err, "Cannot read access list");
+                return;
+            }
+        };
+        self.modified = Some(modified);
+
+        match std::fs::read_to_string(file)
+            .map_err(eyre::Report::from)
+            .and_then(|content| AccessList::parse(&content))
+        {
+            Ok(list) => {
+                let mut current = self.base.clone();
+                current.extend(&list);
+                self.current = current;
+                info!(file = %file.display(), "Loaded access list");
+            }
+            Err(err) => warn!(file = %file.display(), %err, "Invalid access list"),
+        }
+    }
+}
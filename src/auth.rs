@@ -0,0 +1,261 @@
+//! Authentication of the requests exchanged with trusted devices.
+//!
+//! Requests from and to a device with a shared key carry a truncated
+//! HMAC-SHA256 as the last trailer of the packet, right before the optional
+//! checksum:
+//!
+//! | Offset | Size | Field                                             |
+//! |--------|------|---------------------------------------------------|
+//! | 0      | 49   | packet                                            |
+//! | 49     | 28   | optional nonce and tag, see [`crate::encryption`] |
+//! | 49/77  | 8    | MAC                                               |
+//!
+//! The MAC covers every field but `hop_count` and `forwarded_by_addr`, which
+//! are rewritten by the forwarding nodes.
+
+use std::{collections::HashMap, path::Path};
+
+use eyre::eyre;
+use hmac::{Hmac, KeyInit, Mac as _};
+use sha2::Sha256;
+
+use crate::{
+    admission::parse_addr,
+    protocol::{wire_u32, PFPRequest, ProtocolError, PACKET_SIZE},
+    protocol_parser,
+};
+
+pub const MAC_SIZE: usize = 8;
+
+pub type Mac = [u8; MAC_SIZE];
+
+/// Keys shared with the trusted devices, by address.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceKeys {
+    keys: HashMap<u32, Vec<u8>>,
+}
+
+impl DeviceKeys {
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn parse(content: &str) -> crate::Result<Self> {
+        Ok(Self {
+            keys: parse_keys(content)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether the requests of `addr` are authenticated.
+    pub fn contains(&self, addr: u32) -> bool {
+        self.keys.contains_key(&addr)
+    }
+
+    /// MAC of `request` with the key of `addr`, if it has one.
+    pub fn sign(&self, addr: u32, request: &PFPRequest) -> Option<Mac> {
+        let tag = self.hmac(addr, request)?.finalize().into_bytes();
+        Some(tag[..MAC_SIZE].try_into().unwrap())
+    }
+
+    /// Decode a packet, checking its MAC when its source has a key.
+    ///
+    /// The trailers following the packet are returned as is, so forwarded
+    /// requests can carry them along.
+    pub fn open<'a>(&self, packet: &'a [u8]) -> Result<(PFPRequest, &'a [u8]), ProtocolError> {
+        let request = protocol_parser::decode(packet)?;
+        let trailer = packet.get(PACKET_SIZE..).unwrap_or_default();
+
+        if let Some(hmac) = self.hmac(request.source_addr, &request) {
+            let mac = trailer
+                .len()
+                .checked_sub(MAC_SIZE)
+                .map(|start| &trailer[start..])
+                .ok_or(ProtocolError::Unauthenticated)?;
+            hmac.verify_truncated_left(mac)
+                .map_err(|_| ProtocolError::Unauthenticated)?;
+        }
+        Ok((request, trailer))
+    }
+
+    fn hmac(&self, addr: u32, request: &PFPRequest) -> Option<Hmac<Sha256>> {
+        let key = self.keys.get(&addr)?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(&authenticated_data(request));
+        mac.update(&request.payload);
+        Some(mac)
+    }
+}
+
+/// Header fields that are not modified along the way.
+pub fn authenticated_data(request: &PFPRequest) -> Vec<u8> {
+    let mut data = vec![request.command_id];
+    data.extend(wire_u32(request.source_addr));
+    data.extend(wire_u32(request.dest_addr));
+    data.extend([
+        request.request_id,
+        request.request_part,
+        request.request_count,
+    ]);
+    data
+}
+
+/// Parse `<addr> <hex key>` lines.
+///
+/// Empty lines and lines starting with `#` are ignored.
+pub fn parse_keys(content: &str) -> crate::Result<HashMap<u32, Vec<u8>>> {
+    let mut keys = HashMap::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (addr, key) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| eyre!("line {}: expected `<addr> <hex key>`", index + 1))?;
+        let addr =
+            parse_addr(addr).map_err(|err| eyre!("line {}: invalid address: {err}", index + 1))?;
+        let key = parse_hex(key.trim())
+            .ok_or_else(|| eyre!("line {}: invalid hexadecimal key", index + 1))?;
+        keys.insert(addr, key);
+    }
+    Ok(keys)
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if value.is_empty() || value.len() % 2 != 0 || !value.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(value.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{payload::Payload, protocol::Command};
+
+    const KEYED: u32 = 0x1234_5678;
+    const UNKEYED: u32 = 0x0BAD_CAFE;
+
+    fn keys() -> DeviceKeys {
+        DeviceKeys::parse("# trusted devices\n\n0x12345678 00112233445566778899aabbccddeeff\n")
+            .unwrap()
+    }
+
+    fn request(source_addr: u32) -> PFPRequest {
+        PFPRequest::with_payload(
+            source_addr,
+            &Payload::Push {
+                serial: 0xDEAD_BEEF,
+                intensity: 42,
+                sequence: 7,
+            },
+        )
+        .dest_addr(0x0000_0001)
+        .request_id(3)
+        .build()
+    }
+
+    /// Packet sent by `request.source_addr`, MAC included.
+    fn signed(keys: &DeviceKeys, request: &PFPRequest) -> Vec<u8> {
+        let mut packet = Vec::from(request.clone());
+        packet.extend(keys.sign(request.source_addr, request).unwrap());
+        packet
+    }
+
+    #[test]
+    fn sign_open_round_trip() {
+        let keys = keys();
+        let request = request(KEYED);
+        let packet = signed(&keys, &request);
+        let (opened, trailer) = keys.open(&packet).unwrap();
+        assert_eq!(opened, request);
+        assert_eq!(trailer.len(), MAC_SIZE);
+    }
+
+    #[test]
+    fn tampered_packets_are_rejected() {
+        let keys = keys();
+        let packet = signed(&keys, &request(KEYED));
+        // Every byte but `hop_count` and `forwarded_by_addr`, MAC included. A
+        // changed `source_addr` is another device, without a key here
+        let mutable = [1, 2, 3, 4, 5, 10, 11, 12, 13];
+        for index in (0..packet.len()).filter(|index| !mutable.contains(index)) {
+            let mut tampered = packet.clone();
+            tampered[index] ^= 0x01;
+            assert!(
+                matches!(
+                    keys.open(&tampered),
+                    Err(ProtocolError::Unauthenticated | ProtocolError::UnknownCommand(_))
+                ),
+                "byte {index}"
+            );
+        }
+        // A valid command id, so that only the MAC can catch it
+        let mut tampered = packet.clone();
+        tampered[0] = Command::Alive.into();
+        assert_eq!(keys.open(&tampered), Err(ProtocolError::Unauthenticated));
+
+        // Nor can it pass for another keyed device
+        let keys = DeviceKeys::parse("0x12345678 0011\n0x9ABCDEF0 2233").unwrap();
+        let mut tampered = signed(&keys, &request(KEYED));
+        tampered[2..6].copy_from_slice(&wire_u32(0x9ABC_DEF0));
+        assert_eq!(keys.open(&tampered), Err(ProtocolError::Unauthenticated));
+    }
+
+    #[test]
+    fn missing_mac_is_rejected() {
+        let keys = keys();
+        let packet = signed(&keys, &request(KEYED));
+        assert_eq!(
+            keys.open(&packet[..PACKET_SIZE]),
+            Err(ProtocolError::Unauthenticated)
+        );
+        assert_eq!(
+            keys.open(&packet[..packet.len() - 1]),
+            Err(ProtocolError::Unauthenticated)
+        );
+    }
+
+    #[test]
+    fn forwarding_keeps_the_mac_valid() {
+        let keys = keys();
+        let mut packet = signed(&keys, &request(KEYED));
+        // As rewritten by `Relay::forward`
+        packet[1] += 1;
+        packet[10..14].copy_from_slice(&wire_u32(0x0000_0002));
+        let (opened, _) = keys.open(&packet).unwrap();
+        assert_eq!(opened.hop_count, 1);
+        assert_eq!(opened.forwarded_by_addr, 0x0000_0002);
+    }
+
+    #[test]
+    fn unkeyed_sources_pass_through() {
+        let keys = keys();
+        let request = request(UNKEYED);
+        assert_eq!(keys.sign(UNKEYED, &request), None);
+        let packet = Vec::from(request.clone());
+        assert_eq!(keys.open(&packet), Ok((request, &[][..])));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        for content in [
+            "0x12345678 001",
+            "0x12345678 00zz",
+            "0x12345678 +1",
+            "0x12345678",
+            "nope 0011",
+        ] {
+            assert!(parse_keys(content).is_err(), "{content:?}");
+        }
+        assert_eq!(keys().len(), 1);
+    }
+}
@@ -0,0 +1,113 @@
+use clap::ValueEnum;
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC};
+
+use crate::protocol::{wire_u16, wire_u32, ProtocolError};
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Integrity check appended at the end of every packet, see [`crate::protocol`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Checksum {
+    /// No integrity check
+    #[default]
+    None,
+    /// CRC-16/CCITT-FALSE
+    Crc16,
+    /// CRC-32/ISO-HDLC, as used by Ethernet and zlib
+    Crc32,
+}
+
+impl Checksum {
+    pub fn size(&self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    /// Append the checksum of `packet` to it.
+    pub fn seal(&self, mut packet: Vec<u8>) -> Vec<u8> {
+        match self {
+            Checksum::None => (),
+            Checksum::Crc16 => packet.extend_from_slice(&wire_u16(CRC16.checksum(&packet))),
+            Checksum::Crc32 => packet.extend_from_slice(&wire_u32(CRC32.checksum(&packet))),
+        }
+        packet
+    }
+
+    /// Verify and strip the trailing checksum of `packet`.
+    pub fn open<'a>(&self, packet: &'a [u8]) -> Result<&'a [u8], ProtocolError> {
+        let data_len = packet
+            .len()
+            .checked_sub(self.size())
+            .ok_or(ProtocolError::Malformed)?;
+        let (data, checksum) = packet.split_at(data_len);
+        let valid = match self {
+            Checksum::None => true,
+            Checksum::Crc16 => wire_u16(CRC16.checksum(data)) == checksum,
+            Checksum::Crc32 => wire_u32(CRC32.checksum(data)) == checksum,
+        };
+
+        if valid {
+            Ok(data)
+        } else {
+            Err(ProtocolError::ChecksumMismatch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHECKSUMS: [Checksum; 3] = [Checksum::None, Checksum::Crc16, Checksum::Crc32];
+
+    #[test]
+    fn seal_open_round_trip() {
+        for checksum in CHECKSUMS {
+            let sealed = checksum.seal(b"packet".to_vec());
+            assert_eq!(sealed.len(), 6 + checksum.size(), "{checksum:?}");
+            assert_eq!(checksum.open(&sealed), Ok(&b"packet"[..]), "{checksum:?}");
+        }
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(
+            Checksum::Crc16.seal(b"123456789".to_vec())[9..],
+            [0x29, 0xB1]
+        );
+        assert_eq!(
+            Checksum::Crc32.seal(b"123456789".to_vec())[9..],
+            [0xCB, 0xF4, 0x39, 0x26]
+        );
+    }
+
+    #[test]
+    fn flipped_bit() {
+        for checksum in [Checksum::Crc16, Checksum::Crc32] {
+            let sealed = checksum.seal(b"packet".to_vec());
+            for bit in 0..sealed.len() * 8 {
+                let mut corrupted = sealed.clone();
+                corrupted[bit / 8] ^= 1 << (bit % 8);
+                assert_eq!(
+                    checksum.open(&corrupted),
+                    Err(ProtocolError::ChecksumMismatch),
+                    "{checksum:?}, bit {bit}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shorter_than_the_checksum() {
+        assert_eq!(Checksum::Crc16.open(&[0x29]), Err(ProtocolError::Malformed));
+        assert_eq!(
+            Checksum::Crc32.open(&[0xCB, 0xF4, 0x39]),
+            Err(ProtocolError::Malformed)
+        );
+        assert_eq!(Checksum::None.open(&[]), Ok(&[][..]));
+    }
+}
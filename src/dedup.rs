@@ -0,0 +1,75 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::protocol::{PFPMessage, PFPRequest};
+
+/// How long a request is remembered, must stay well below the time it takes
+/// a device to wrap its `u8` request ids around.
+pub const DEDUP_WINDOW: Duration = Duration::from_secs(30);
+
+/// Remembers recent requests to drop the copies flooded through other forwarders.
+///
+/// Requests are keyed by `(source_addr, command_id, request_id, request_part)`:
+/// responses echo the id of the request they answer, so the same source can
+/// legitimately reuse an id for different commands.
+pub struct DedupCache {
+    seen: HashMap<(u32, u8, u8, u8), Instant>,
+    window: Duration,
+}
+
+impl DedupCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            seen: HashMap::new(),
+            window,
+        }
+    }
+
+    /// Whether this is the first copy of `request` within the window.
+    pub fn insert(&mut self, request: &PFPRequest, now: Instant) -> bool {
+        if self.contains(request, now) {
+            return false;
+        }
+        self.seen.insert(key(request), now);
+        true
+    }
+
+    /// Whether a copy of `request` was seen within the window, without
+    /// remembering this one.
+    pub fn contains(&self, request: &PFPRequest, now: Instant) -> bool {
+        self.seen
+            .get(&key(request))
+            .is_some_and(|seen| now.duration_since(*seen) <= self.window)
+    }
+
+    /// Remember every part of a `message` sent over `request_count` parts.
+    pub fn insert_message(&mut self, message: &PFPMessage, request_count: u8, now: Instant) {
+        for request_part in 0..request_count.max(1) {
+            self.seen.insert(
+                (
+                    message.source_addr,
+                    message.command_id,
+                    message.request_id,
+                    request_part,
+                ),
+                now,
+            );
+        }
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let window = self.window;
+        self.seen
+            .retain(|_, seen| now.duration_since(*seen) <= window);
+    }
+}
+
+fn key(request: &PFPRequest) -> (u32, u8, u8, u8) {
+    (
+        request.source_addr,
+        request.command_id,
+        request.request_id,
+        request.request_part,
+    )
+}
@@ -0,0 +1,134 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::protocol::{Command, PFPRequest};
+
+/// Onboarding progress of a device, driven by [`crate::handshake::Handshake`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// Heard of, not identified yet
+    Discovered,
+    /// `Ident` sent, waiting for `Tnedi`
+    Identifying,
+    /// `TrustB` sent, waiting for `TrustT`
+    Trusting,
+    /// Handshake completed
+    Trusted,
+    /// Handshake gave up after too many retries, started over later
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub addr: u32,
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub hop_count: u8,
+    pub forwarded_by_addr: u32,
+    pub last_request_id: u8,
+    pub state: DeviceState,
+    /// Serial number reported in `Tnedi`
+    pub serial: Option<u32>,
+    /// Last direct `OlehL` answer, set when the device is a one-hop neighbor
+    pub link_seen: Option<Instant>,
+    /// When the handshake last failed
+    pub failed_at: Option<Instant>,
+}
+
+impl Device {
+    /// Whether the device answered a link-level discovery within `max_age`.
+    pub fn is_neighbor(&self, now: Instant, max_age: Duration) -> bool {
+        self.link_seen
+            .is_some_and(|link_seen| now.duration_since(link_seen) <= max_age)
+    }
+}
+
+/// Devices known by the relay, keyed by address.
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    devices: HashMap<u32, Device>,
+}
+
+impl DeviceRegistry {
+    /// Update the registry from an incoming request.
+    ///
+    /// Only discovery, heartbeat and data traffic can register a new device,
+    /// any other request only refreshes an already known one.
+    pub fn observe(&mut self, request: &PFPRequest, now: Instant) -> Option<&Device> {
+        let known = self.devices.contains_key(&request.source_addr);
+        let registers = matches!(
+            Command::try_from(request.command_id),
+            Ok(Command::HeloP | Command::OlehP | Command::OlehL | Command::Alive | Command::Push)
+        );
+        if !known && !registers {
+            return None;
+        }
+
+        let device = self.devices.entry(request.source_addr).or_insert(Device {
+            addr: request.source_addr,
+            first_seen: now,
+            last_seen: now,
+            hop_count: request.hop_count,
+            forwarded_by_addr: request.forwarded_by_addr,
+            last_request_id: request.request_id,
+            state: DeviceState::Discovered,
+            serial: None,
+            link_seen: None,
+            failed_at: None,
+        });
+
+        device.last_seen = now;
+        device.hop_count = request.hop_count;
+        device.forwarded_by_addr = request.forwarded_by_addr;
+        device.last_request_id = request.request_id;
+        if request.command_id == Command::OlehL
+            && request.hop_count == 0
+            && request.forwarded_by_addr == 0
+        {
+            device.link_seen = Some(now);
+        }
+
+        Some(device)
+    }
+
+    pub fn get_mut(&mut self, addr: u32) -> Option<&mut Device> {
+        self.devices.get_mut(&addr)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Device> {
+        self.devices.values_mut()
+    }
+
+    /// Remove and return the devices not seen for `ttl`.
+    pub fn expire(&mut self, now: Instant, ttl: Duration) -> Vec<Device> {
+        let expired = self
+            .devices
+            .values()
+            .filter(|device| now.duration_since(device.last_seen) > ttl)
+            .map(|device| device.addr)
+            .collect::<Vec<_>>();
+
+        expired
+            .into_iter()
+            .filter_map(|addr| self.devices.remove(&addr))
+            .collect()
+    }
+
+    /// Addresses of the one-hop neighbors, see [`Device::is_neighbor`].
+    pub fn neighbors(&self, now: Instant, max_age: Duration) -> Vec<u32> {
+        self.devices
+            .values()
+            .filter(|device| device.is_neighbor(now, max_age))
+            .map(|device| device.addr)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+}
@@ -0,0 +1,234 @@
+//! Encryption of the payloads exchanged with devices sharing a key.
+//!
+//! The payload is encrypted in place with ChaCha20-Poly1305, the random nonce
+//! and the tag are carried right after the packet:
+//!
+//! | Offset | Size | Field  |
+//! |--------|------|--------|
+//! | 0      | 49   | packet |
+//! | 49     | 12   | nonce  |
+//! | 61     | 16   | tag    |
+//!
+//! The header fields covered by the MAC of [`crate::auth`] are authenticated
+//! as associated data.
+
+use std::{collections::HashMap, fmt, path::Path};
+
+use chacha20poly1305::{
+    aead::{AeadInOut, Generate, KeyInit},
+    ChaCha20Poly1305, Nonce, Tag,
+};
+use eyre::eyre;
+
+use crate::{
+    auth::{authenticated_data, parse_keys},
+    protocol::{PFPRequest, ProtocolError},
+};
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TAG_SIZE: usize = 16;
+
+/// Keys used to encrypt the payloads of each device, by address.
+#[derive(Clone, Default)]
+pub struct DeviceCiphers {
+    ciphers: HashMap<u32, ChaCha20Poly1305>,
+}
+
+/// Only shows the addresses, keys are kept out of the logs.
+impl fmt::Debug for DeviceCiphers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.ciphers.keys()).finish()
+    }
+}
+
+impl DeviceCiphers {
+    pub fn load(path: &Path) -> crate::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse `<addr> <hex key>` lines, with 32 bytes keys.
+    pub fn parse(content: &str) -> crate::Result<Self> {
+        let ciphers = parse_keys(content)?
+            .into_iter()
+            .map(|(addr, key)| {
+                ChaCha20Poly1305::new_from_slice(&key)
+                    .map(|cipher| (addr, cipher))
+                    .map_err(|_| eyre!("key of {addr}: expected {KEY_SIZE} bytes"))
+            })
+            .collect::<crate::Result<_>>()?;
+        Ok(Self { ciphers })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ciphers.len()
+    }
+
+    /// Encrypt the payload of `request` with the key of `addr`, if it has one.
+    ///
+    /// Returns the nonce and tag to append to the packet.
+    pub fn seal(&self, addr: u32, request: &mut PFPRequest) -> Option<Vec<u8>> {
+        let cipher = self.ciphers.get(&addr)?;
+        let nonce = Nonce::generate();
+        let tag = cipher
+            .encrypt_inout_detached(
+                &nonce,
+                &authenticated_data(request),
+                request.payload.as_mut_slice().into(),
+            )
+            .ok()?;
+
+        let mut trailer = nonce.to_vec();
+        trailer.extend_from_slice(&tag);
+        Some(trailer)
+    }
+
+    /// Decrypt the payload of `request` when its source has a key, using the
+    /// nonce and tag found at the start of `trailer`.
+    pub fn open(&self, request: &mut PFPRequest, trailer: &[u8]) -> Result<(), ProtocolError> {
+        let Some(cipher) = self.ciphers.get(&request.source_addr) else {
+            return Ok(());
+        };
+        if trailer.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(ProtocolError::Unauthenticated);
+        }
+
+        let (nonce, tag) = trailer[..NONCE_SIZE + TAG_SIZE].split_at(NONCE_SIZE);
+        let nonce = Nonce::try_from(nonce).unwrap();
+        let tag = Tag::try_from(tag).unwrap();
+        cipher
+            .decrypt_inout_detached(
+                &nonce,
+                &authenticated_data(request),
+                request.payload.as_mut_slice().into(),
+                &tag,
+            )
+            .map_err(|_| ProtocolError::Unauthenticated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::DeviceKeys, payload::Payload, protocol::PACKET_SIZE};
+
+    const KEYED: u32 = 0x1234_5678;
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn ciphers() -> DeviceCiphers {
+        DeviceCiphers::parse(&format!("0x12345678 {KEY}")).unwrap()
+    }
+
+    fn request() -> PFPRequest {
+        PFPRequest::with_payload(
+            KEYED,
+            &Payload::Push {
+                serial: 0xDEAD_BEEF,
+                intensity: 42,
+                sequence: 7,
+            },
+        )
+        .dest_addr(0x0000_0001)
+        .request_id(3)
+        .build()
+    }
+
+    /// `request` encrypted by its source, and the trailer to send with it.
+    fn sealed() -> (PFPRequest, Vec<u8>) {
+        let mut request = request();
+        let trailer = ciphers().seal(KEYED, &mut request).unwrap();
+        (request, trailer)
+    }
+
+    #[test]
+    fn seal_open_round_trip() {
+        let (mut request, trailer) = sealed();
+        assert_eq!(trailer.len(), NONCE_SIZE + TAG_SIZE);
+        assert_ne!(request.payload, self::request().payload);
+
+        ciphers().open(&mut request, &trailer).unwrap();
+        assert_eq!(request, self::request());
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let ciphers = ciphers();
+        let (request, trailer) = sealed();
+
+        let mut tampered = request.clone();
+        tampered.payload[5] ^= 0x01;
+        assert_eq!(
+            ciphers.open(&mut tampered, &trailer),
+            Err(ProtocolError::Unauthenticated)
+        );
+
+        let mut tampered = request.clone();
+        tampered.request_id += 1;
+        assert_eq!(
+            ciphers.open(&mut tampered, &trailer),
+            Err(ProtocolError::Unauthenticated)
+        );
+
+        // Nonce, then tag
+        for index in [0, NONCE_SIZE - 1, NONCE_SIZE, NONCE_SIZE + TAG_SIZE - 1] {
+            let mut trailer = trailer.clone();
+            trailer[index] ^= 0x01;
+            assert_eq!(
+                ciphers.open(&mut request.clone(), &trailer),
+                Err(ProtocolError::Unauthenticated),
+                "byte {index}"
+            );
+        }
+    }
+
+    #[test]
+    fn short_trailer_is_rejected() {
+        let (mut request, trailer) = sealed();
+        for len in [0, NONCE_SIZE, NONCE_SIZE + TAG_SIZE - 1] {
+            assert_eq!(
+                ciphers().open(&mut request, &trailer[..len]),
+                Err(ProtocolError::Unauthenticated),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn unkeyed_sources_are_left_alone() {
+        let mut request = PFPRequest::with_payload(0x0BAD_CAFE, &Payload::Alive).build();
+        let plain = request.clone();
+        assert_eq!(ciphers().seal(0x0BAD_CAFE, &mut request), None);
+        assert_eq!(ciphers().open(&mut request, &[]), Ok(()));
+        assert_eq!(request, plain);
+    }
+
+    #[test]
+    fn encrypt_then_mac() {
+        let keys = DeviceKeys::parse("0x12345678 00112233445566778899aabbccddeeff").unwrap();
+        let ciphers = ciphers();
+
+        // As sent by the device
+        let (request, mut trailer) = sealed();
+        trailer.extend(keys.sign(KEYED, &request).unwrap());
+        let mut packet = Vec::from(request);
+        packet.extend_from_slice(&trailer);
+
+        // As read by the relay
+        let (mut opened, trailer) = keys.open(&packet).unwrap();
+        assert_eq!(trailer.len(), NONCE_SIZE + TAG_SIZE + crate::auth::MAC_SIZE);
+        ciphers.open(&mut opened, trailer).unwrap();
+        assert_eq!(opened, self::request());
+
+        // The MAC covers the ciphertext
+        packet[PACKET_SIZE - 1] ^= 0x01;
+        assert_eq!(keys.open(&packet), Err(ProtocolError::Unauthenticated));
+    }
+
+    #[test]
+    fn keys_must_be_32_bytes() {
+        assert!(DeviceCiphers::parse(&format!("0x12345678 {}", &KEY[2..])).is_err());
+        assert!(DeviceCiphers::parse(&format!("0x12345678 {KEY}00")).is_err());
+        assert!(DeviceCiphers::parse("0x12345678 0011").is_err());
+        assert_eq!(ciphers().len(), 1);
+    }
+}
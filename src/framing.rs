@@ -0,0 +1,361 @@
+use std::{fmt, io};
+
+use bytes::{Buf, BufMut, BytesMut};
+use clap::ValueEnum;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::read_until::{Delimiter, ReadUntilPat};
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+/// Longest frame accepted on the wire, enough for a fully escaped SLIP packet
+/// with every trailer.
+pub const MAX_FRAME_SIZE: usize = 256;
+
+/// How packets are delimited on the serial link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Framing {
+    /// Legacy `\r\n` terminated frames, not binary safe
+    Line,
+    /// Consistent Overhead Byte Stuffing, frames end with `0x00`
+    Cobs,
+    /// Serial Line Internet Protocol, frames end with `0xC0`
+    Slip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramingError {
+    /// A COBS block points past the end of the frame.
+    TruncatedCobs,
+    /// A SLIP escape byte is not followed by a valid escape code.
+    InvalidSlipEscape,
+    /// No delimiter was found within [`MAX_FRAME_SIZE`] bytes.
+    TooLong,
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FramingError::TruncatedCobs => write!(f, "truncated COBS frame"),
+            FramingError::InvalidSlipEscape => write!(f, "invalid SLIP escape sequence"),
+            FramingError::TooLong => write!(f, "frame longer than {MAX_FRAME_SIZE} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for FramingError {}
+
+impl Framing {
+    /// Bytes marking the end of a frame on the wire.
+    pub fn delimiter(&self) -> &'static [u8] {
+        match self {
+            Framing::Line => b"\r\n",
+            Framing::Cobs => &[0x00],
+            Framing::Slip => &[SLIP_END],
+        }
+    }
+
+    /// Encode a packet into a frame, delimiter included.
+    pub fn encode(&self, packet: &[u8]) -> Vec<u8> {
+        let mut frame = match self {
+            Framing::Line => packet.to_vec(),
+            Framing::Cobs => cobs_encode(packet),
+            Framing::Slip => slip_encode(packet),
+        };
+        frame.extend_from_slice(self.delimiter());
+        frame
+    }
+
+    /// Decode a frame back into a packet, a trailing delimiter is ignored.
+    pub fn decode(&self, frame: &[u8]) -> Result<Vec<u8>, FramingError> {
+        let frame = frame.strip_suffix(self.delimiter()).unwrap_or(frame);
+        match self {
+            Framing::Line => Ok(frame.to_vec()),
+            Framing::Cobs => cobs_decode(frame),
+            Framing::Slip => slip_decode(frame),
+        }
+    }
+}
+
+/// Codec splitting the serial stream into frames.
+///
+/// A frame that cannot be decoded is yielded as an error item rather than
+/// failing the stream, the following frames are still readable.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    framing: Framing,
+    /// Skipping the rest of a frame over [`MAX_FRAME_SIZE`]
+    discarding: bool,
+}
+
+impl FrameCodec {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            discarding: false,
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Result<Vec<u8>, FramingError>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let pattern = self.framing.delimiter();
+        if self.discarding {
+            match src
+                .windows(pattern.len())
+                .position(|window| window == pattern)
+            {
+                Some(position) => {
+                    src.advance(position + pattern.len());
+                    self.discarding = false;
+                }
+                None => {
+                    // Keep what might be the start of a split delimiter
+                    src.advance((src.len() + 1).saturating_sub(pattern.len()));
+                    return Ok(None);
+                }
+            }
+        }
+
+        let delimiter = Delimiter::new(pattern).max_frame_len(MAX_FRAME_SIZE);
+        let mut reader = &src[..];
+        let mut frame = Vec::new();
+        let read = reader.read_until_pat(&delimiter, &mut frame);
+        let mut consumed = src.len() - reader.len();
+
+        let frame = match read {
+            Ok(0) => return Ok(None),
+            Ok(_) => self.framing.decode(&frame),
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                // The delimiter ending the frame has not been received yet
+                if !src[..consumed].ends_with(pattern) {
+                    self.discarding = true;
+                    consumed = (consumed + 1).saturating_sub(pattern.len());
+                }
+                Err(FramingError::TooLong)
+            }
+            Err(err) => return Err(err),
+        };
+        src.advance(consumed);
+        Ok(Some(frame))
+    }
+}
+
+impl Encoder<Vec<u8>> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Vec<u8>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.put_slice(&self.framing.encode(&packet));
+        Ok(())
+    }
+}
+
+fn cobs_encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + packet.len() / 254 + 2);
+    let mut code_index = 0;
+    let mut code = 1u8;
+    encoded.push(0);
+
+    for &byte in packet {
+        if byte == 0 {
+            encoded[code_index] = code;
+            code_index = encoded.len();
+            encoded.push(0);
+            code = 1;
+        } else {
+            encoded.push(byte);
+            code += 1;
+            if code == 0xFF {
+                encoded[code_index] = code;
+                code_index = encoded.len();
+                encoded.push(0);
+                code = 1;
+            }
+        }
+    }
+    encoded[code_index] = code;
+
+    encoded
+}
+
+fn cobs_decode(frame: &[u8]) -> Result<Vec<u8>, FramingError> {
+    let mut decoded = Vec::with_capacity(frame.len());
+    let mut index = 0;
+
+    while index < frame.len() {
+        let code = frame[index] as usize;
+        if code == 0 || index + code > frame.len() {
+            return Err(FramingError::TruncatedCobs);
+        }
+        decoded.extend_from_slice(&frame[index + 1..index + code]);
+        index += code;
+        if code < 0xFF && index < frame.len() {
+            decoded.push(0);
+        }
+    }
+
+    Ok(decoded)
+}
+
+fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + 2);
+    for &byte in packet {
+        match byte {
+            SLIP_END => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            _ => encoded.push(byte),
+        }
+    }
+    encoded
+}
+
+fn slip_decode(frame: &[u8]) -> Result<Vec<u8>, FramingError> {
+    let mut decoded = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == SLIP_ESC {
+            match bytes.next() {
+                Some(&SLIP_ESC_END) => decoded.push(SLIP_END),
+                Some(&SLIP_ESC_ESC) => decoded.push(SLIP_ESC),
+                _ => return Err(FramingError::InvalidSlipEscape),
+            }
+        } else {
+            decoded.push(byte);
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAMINGS: [Framing; 3] = [Framing::Line, Framing::Cobs, Framing::Slip];
+
+    fn packets() -> Vec<Vec<u8>> {
+        vec![
+            vec![],
+            vec![0x00],
+            vec![0x00, 0x00, 0x01, 0x00],
+            vec![SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC, SLIP_END],
+            b"\r\n\r\n".to_vec(),
+            vec![0x11; 254],
+            vec![0x11; 255],
+            vec![0x11; 600],
+            [vec![0x11; 254], vec![0x00], vec![0x22; 300]].concat(),
+            (0..=255).collect(),
+        ]
+    }
+
+    #[test]
+    fn binary_frames_round_trip() {
+        for framing in [Framing::Cobs, Framing::Slip] {
+            for packet in packets() {
+                let frame = framing.encode(&packet);
+                let delimiter = framing.delimiter();
+                assert!(frame.ends_with(delimiter));
+                assert!(
+                    !frame[..frame.len() - delimiter.len()]
+                        .windows(delimiter.len())
+                        .any(|window| window == delimiter),
+                    "{framing:?} leaks the delimiter in {packet:?}"
+                );
+                assert_eq!(framing.decode(&frame).unwrap(), packet, "{framing:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn line_frames_round_trip() {
+        let frame = Framing::Line.encode(b"hello");
+        assert_eq!(frame, b"hello\r\n");
+        assert_eq!(Framing::Line.decode(&frame).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn codec_splits_frames() {
+        for framing in FRAMINGS {
+            let mut codec = FrameCodec::new(framing);
+            let packets = [b"first".to_vec(), b"second".to_vec()];
+            let mut stream = BytesMut::new();
+            for packet in &packets {
+                codec.encode(packet.clone(), &mut stream).unwrap();
+            }
+
+            let decoded = decode_bytewise(&mut codec, &stream);
+            let decoded: Vec<_> = decoded.into_iter().map(Result::unwrap).collect();
+            assert_eq!(decoded, packets, "{framing:?}");
+        }
+    }
+
+    #[test]
+    fn codec_skips_long_frames() {
+        for framing in FRAMINGS {
+            let mut codec = FrameCodec::new(framing);
+            let mut stream = BytesMut::from(&[0x11; MAX_FRAME_SIZE * 3][..]);
+            stream.extend_from_slice(framing.delimiter());
+            codec.encode(b"next".to_vec(), &mut stream).unwrap();
+
+            assert_eq!(
+                decode_bytewise(&mut codec, &stream),
+                [Err(FramingError::TooLong), Ok(b"next".to_vec())],
+                "{framing:?}"
+            );
+
+            // Whole, the delimiter is found right away
+            let mut src = stream.clone();
+            assert_eq!(
+                codec.decode(&mut src).unwrap(),
+                Some(Err(FramingError::TooLong))
+            );
+            assert_eq!(codec.decode(&mut src).unwrap(), Some(Ok(b"next".to_vec())));
+        }
+    }
+
+    /// Decode `stream` fed one byte at a time, as a slow UART would.
+    fn decode_bytewise(
+        codec: &mut FrameCodec,
+        stream: &[u8],
+    ) -> Vec<Result<Vec<u8>, FramingError>> {
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for &byte in stream {
+            src.put_u8(byte);
+            while let Some(frame) = codec.decode(&mut src).unwrap() {
+                decoded.push(frame);
+            }
+            assert!(src.len() <= MAX_FRAME_SIZE + 2, "buffer keeps growing");
+        }
+        decoded
+    }
+
+    #[test]
+    fn truncated_cobs() {
+        assert_eq!(
+            Framing::Cobs.decode(&[0x05, 0x01, 0x02]),
+            Err(FramingError::TruncatedCobs)
+        );
+        assert_eq!(
+            Framing::Cobs.decode(&[0x02, 0x01, 0x00, 0x01]),
+            Err(FramingError::TruncatedCobs)
+        );
+    }
+
+    #[test]
+    fn invalid_slip_escape() {
+        assert_eq!(
+            Framing::Slip.decode(&[0x01, SLIP_ESC, 0x42]),
+            Err(FramingError::InvalidSlipEscape)
+        );
+        assert_eq!(
+            Framing::Slip.decode(&[0x01, SLIP_ESC]),
+            Err(FramingError::InvalidSlipEscape)
+        );
+    }
+}
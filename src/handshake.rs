@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{
+    devices::{Device, DeviceState},
+    payload::Payload,
+};
+
+/// Delay before the handshake of a device that failed it is started over.
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// What the relay has to send to move a handshake forward.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeAction {
+    /// Send the payload to the device
+    Reply(Payload),
+    /// Send the payload to every node
+    Broadcast(Payload),
+}
+
+/// Onboarding state machine: `HeloP` → `OlehP` → `Ident` → `Tnedi` → `TrustB` → `TrustT`.
+///
+/// Devices are either answered with `OlehP` when they send `HeloP`, or
+/// reply to the relay `HeloP` with `OlehP`. The relay then asks them to
+/// identify themselves and establishes trust, announcing them with `Add`
+/// once done. Retries of `Ident` and `TrustB` are left to
+/// [`crate::outbound::Outbound`], which calls [`Handshake::on_timeout`]
+/// when the device never answers. The handshake of a failed device starts
+/// over after [`RETRY_BACKOFF`].
+pub struct Handshake;
+
+impl Handshake {
+    /// React to a payload received from `device`.
+    pub fn on_payload(&self, device: &mut Device, payload: &Payload) -> Vec<HandshakeAction> {
+        match (device.state, payload) {
+            // Also restarts the handshake of known devices that rebooted
+            (_, Payload::HeloP) => {
+                let mut actions = vec![HandshakeAction::Reply(Payload::OlehP)];
+                actions.extend(self.step(device, DeviceState::Identifying));
+                actions
+            }
+            (DeviceState::Discovered | DeviceState::Failed, Payload::OlehP) => {
+                self.step(device, DeviceState::Identifying)
+            }
+            (DeviceState::Identifying, Payload::Tnedi { serial }) => {
+                device.serial = Some(*serial);
+                self.step(device, DeviceState::Trusting)
+            }
+            (DeviceState::Trusting, Payload::TrustT) => {
+                device.state = DeviceState::Trusted;
+                vec![HandshakeAction::Broadcast(Payload::Add {
+                    addr: device.addr,
+                })]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Start the handshake of newly discovered devices, and retry the failed
+    /// ones.
+    pub fn poll(&self, device: &mut Device, now: Instant) -> Vec<HandshakeAction> {
+        match device.state {
+            DeviceState::Discovered => self.step(device, DeviceState::Identifying),
+            DeviceState::Failed
+                if device
+                    .failed_at
+                    .is_none_or(|failed_at| now.duration_since(failed_at) >= RETRY_BACKOFF) =>
+            {
+                self.step(device, DeviceState::Identifying)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Give up on `device` once a handshake request was never answered.
+    pub fn on_timeout(&self, device: &mut Device, now: Instant) {
+        if matches!(
+            device.state,
+            DeviceState::Identifying | DeviceState::Trusting
+        ) {
+            device.state = DeviceState::Failed;
+            device.failed_at = Some(now);
+        }
+    }
+
+    /// Move `device` to `state` and send the matching request.
+    fn step(&self, device: &mut Device, state: DeviceState) -> Vec<HandshakeAction> {
+        let request = match state {
+            DeviceState::Identifying => Payload::Ident,
+            DeviceState::Trusting => Payload::TrustB,
+            _ => unreachable!("{state:?} is not a handshake step"),
+        };
+
+        device.state = state;
+        vec![HandshakeAction::Reply(request)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: u32 = 0x1234_5678;
+
+    fn device(state: DeviceState) -> Device {
+        let now = Instant::now();
+        Device {
+            addr: ADDR,
+            first_seen: now,
+            last_seen: now,
+            hop_count: 0,
+            forwarded_by_addr: 0,
+            last_request_id: 0,
+            state,
+            serial: None,
+            link_seen: None,
+            failed_at: None,
+        }
+    }
+
+    #[test]
+    fn helop_starts_identifying() {
+        for state in [
+            DeviceState::Discovered,
+            DeviceState::Trusted,
+            DeviceState::Failed,
+        ] {
+            let mut device = device(state);
+            assert_eq!(
+                Handshake.on_payload(&mut device, &Payload::HeloP),
+                [
+                    HandshakeAction::Reply(Payload::OlehP),
+                    HandshakeAction::Reply(Payload::Ident)
+                ]
+            );
+            assert_eq!(device.state, DeviceState::Identifying);
+        }
+    }
+
+    #[test]
+    fn discovered_devices_are_identified() {
+        let mut device = device(DeviceState::Discovered);
+        assert_eq!(
+            Handshake.poll(&mut device, Instant::now()),
+            [HandshakeAction::Reply(Payload::Ident)]
+        );
+        assert_eq!(device.state, DeviceState::Identifying);
+        assert!(Handshake.poll(&mut device, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn tnedi_starts_trusting() {
+        let mut device = device(DeviceState::Identifying);
+        let tnedi = Payload::Tnedi { serial: 42 };
+        assert_eq!(
+            Handshake.on_payload(&mut device, &tnedi),
+            [HandshakeAction::Reply(Payload::TrustB)]
+        );
+        assert_eq!(device.state, DeviceState::Trusting);
+        assert_eq!(device.serial, Some(42));
+    }
+
+    #[test]
+    fn trustt_announces_the_device() {
+        let mut device = device(DeviceState::Trusting);
+        assert_eq!(
+            Handshake.on_payload(&mut device, &Payload::TrustT),
+            [HandshakeAction::Broadcast(Payload::Add { addr: ADDR })]
+        );
+        assert_eq!(device.state, DeviceState::Trusted);
+    }
+
+    #[test]
+    fn unexpected_payloads_are_ignored() {
+        let mut device = device(DeviceState::Identifying);
+        assert!(Handshake
+            .on_payload(&mut device, &Payload::TrustT)
+            .is_empty());
+        assert_eq!(device.state, DeviceState::Identifying);
+    }
+
+    #[test]
+    fn timeout_fails_then_retries() {
+        for state in [DeviceState::Identifying, DeviceState::Trusting] {
+            let mut device = device(state);
+            let failed_at = Instant::now();
+            Handshake.on_timeout(&mut device, failed_at);
+            assert_eq!(device.state, DeviceState::Failed);
+
+            assert!(Handshake
+                .poll(&mut device, failed_at + RETRY_BACKOFF / 2)
+                .is_empty());
+            assert_eq!(device.state, DeviceState::Failed);
+
+            assert_eq!(
+                Handshake.poll(&mut device, failed_at + RETRY_BACKOFF),
+                [HandshakeAction::Reply(Payload::Ident)]
+            );
+            assert_eq!(device.state, DeviceState::Identifying);
+        }
+    }
+
+    #[test]
+    fn timeout_keeps_trusted_devices() {
+        let mut device = device(DeviceState::Trusted);
+        Handshake.on_timeout(&mut device, Instant::now());
+        assert_eq!(device.state, DeviceState::Trusted);
+    }
+}
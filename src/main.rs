@@ -1,25 +1,53 @@
-use std::{env::set_var, sync::Arc};
+use std::{env::set_var, path::PathBuf, sync::Arc, time::Duration};
 
 use clap::Parser;
+use futures::future::BoxFuture;
 use logger::setup_logger;
 use mosquitto_rs::Message;
 use mqtt::SimpleMQTT;
 use serial::SimpleSerial;
-use tokio::{sync::oneshot, sync::RwLock};
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    sync::oneshot,
+    sync::RwLock,
+};
 use tracing::{debug, info, warn};
 
 use crate::{
-    protocol::{Command, PFPRequest},
-    relay::Relay,
+    admission::AccessList,
+    auth::DeviceKeys,
+    checksum::Checksum,
+    encryption::DeviceCiphers,
+    framing::Framing,
+    payload::Payload,
+    protocol::PFPMessage,
+    read_until::{AsyncReadUntilPat, Delimiter},
+    relay::{Relay, RelayConfig, RelayEvent},
+    topology::{Topology, TopologyFormat},
 };
 
+mod admission;
+mod auth;
+mod checksum;
+mod dedup;
+mod devices;
+mod encryption;
+mod framing;
+mod handshake;
 mod logger;
 mod mqtt;
+mod outbound;
+mod payload;
 mod protocol;
 mod protocol_parser;
 mod read_until;
+mod reassembly;
 mod relay;
+mod replay;
+mod request_id;
+mod routing;
 mod serial;
+mod topology;
 
 pub type Result<T> = eyre::Result<T>;
 
@@ -32,6 +60,9 @@ struct Cli {
     /// Serial baud rate
     #[clap(short = 'b', long, env, default_value = "38400")]
     pub serial_baud_rate: u32,
+    /// Serial framing
+    #[clap(short = 'f', long, env, value_enum, default_value = "cobs")]
+    pub serial_framing: Framing,
     #[clap(short, long = "verbose", action = clap::ArgAction::Count)]
     pub verbosity: u8,
     #[clap(subcommand)]
@@ -44,6 +75,8 @@ enum SubCommand {
     Relay(CliRelay),
     /// Bridge to simulation
     Simulator(CliSimulation),
+    /// Listen to the mesh and print its topology
+    Topology(CliTopology),
     /// Read UART
     Debug,
 }
@@ -51,6 +84,12 @@ enum SubCommand {
 #[derive(Debug, Parser)]
 #[clap(author, version, about, long_about = None)]
 struct CliRelay {
+    /// Address of the relay on the mesh
+    #[clap(long, env, default_value = "1", value_parser = admission::parse_addr)]
+    pub relay_id: u32,
+    /// Use the serial number of the attached micro:bit as relay address
+    #[clap(long, env, action = clap::ArgAction::SetTrue, conflicts_with = "relay_id")]
+    pub relay_id_from_device: bool,
     /// MQTT server host
     #[clap(short = 'H', long, env, default_value = "localhost")]
     pub mqtt_host: String,
@@ -63,6 +102,33 @@ struct CliRelay {
     /// Do not connect to MQTT server
     #[clap(long, env, action = clap::ArgAction::SetTrue)]
     pub dry_mqtt: bool,
+    /// Integrity check appended to every packet
+    #[clap(long, env, value_enum, default_value = "none")]
+    pub checksum: Checksum,
+    /// Seconds without traffic before a device is considered offline
+    #[clap(long, env, default_value = "180")]
+    pub device_ttl: u64,
+    /// Requests that went through more hops are dropped
+    #[clap(long, env, default_value = "8")]
+    pub max_hops: u8,
+    /// Forward the requests addressed to other nodes, extending the mesh
+    #[clap(long, env, action = clap::ArgAction::SetTrue)]
+    pub forwarding: bool,
+    /// Device addresses allowed to join, everyone when empty
+    #[clap(long, env, value_delimiter = ',', value_parser = admission::parse_addr)]
+    pub allow: Vec<u32>,
+    /// Device addresses denied from joining
+    #[clap(long, env, value_delimiter = ',', value_parser = admission::parse_addr)]
+    pub deny: Vec<u32>,
+    /// File of `allow <addr>` / `deny <addr>` lines, reloaded when it changes
+    #[clap(long, env)]
+    pub access_list: Option<PathBuf>,
+    /// File of `<addr> <hex key>` lines, authenticating the requests of these devices
+    #[clap(long, env)]
+    pub device_keys: Option<PathBuf>,
+    /// File of `<addr> <hex key>` lines, encrypting the payloads of these devices
+    #[clap(long, env)]
+    pub encryption_keys: Option<PathBuf>,
 }
 
 #[derive(Debug, Parser)]
@@ -82,6 +148,23 @@ struct CliSimulation {
     pub dry_mqtt: bool,
 }
 
+#[derive(Debug, Parser)]
+#[clap(author, version, about, long_about = None)]
+struct CliTopology {
+    /// Address of the relay, root of the topology
+    #[clap(long, env, default_value = "1", value_parser = admission::parse_addr)]
+    pub relay_id: u32,
+    /// Integrity check appended to every packet
+    #[clap(long, env, value_enum, default_value = "none")]
+    pub checksum: Checksum,
+    /// Output format
+    #[clap(short, long, value_enum, default_value = "json")]
+    pub format: TopologyFormat,
+    /// Seconds to listen for before printing the topology
+    #[clap(short, long, default_value = "60")]
+    pub duration: u64,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install().unwrap();
@@ -111,15 +194,39 @@ async fn main() -> Result<()> {
         });
     }
 
-    let mut serial = SimpleSerial::new(&args.serial_port, args.serial_baud_rate)?;
+    let serial = SimpleSerial::new(
+        &args.serial_port,
+        args.serial_baud_rate,
+        args.serial_framing,
+    )?;
     info!(
         serial = args.serial_port,
         baudrate = args.serial_baud_rate,
+        framing = ?args.serial_framing,
+        wire_version = protocol::WIRE_VERSION,
         "Connected to serial port"
     );
 
     match args.subcommand {
         SubCommand::Relay(args) => {
+            let relay_id = if args.relay_id_from_device {
+                relay::query_relay_id(&serial, args.checksum).await?
+            } else {
+                relay::validate_relay_id(args.relay_id)?
+            };
+            info!(relay_id = format!("{relay_id:#010x}"), "Relay address");
+
+            let device_keys = match &args.device_keys {
+                Some(path) => DeviceKeys::load(path)?,
+                None => DeviceKeys::default(),
+            };
+            info!(devices = device_keys.len(), "Loaded device keys");
+            let device_ciphers = match &args.encryption_keys {
+                Some(path) => DeviceCiphers::load(path)?,
+                None => DeviceCiphers::default(),
+            };
+            info!(devices = device_ciphers.len(), "Loaded encryption keys");
+
             let mqtt = Arc::new(RwLock::new(
                 SimpleMQTT::new(&args.mqtt_host, args.mqtt_port, args.dry_mqtt).await?,
             ));
@@ -128,34 +235,84 @@ async fn main() -> Result<()> {
                 port = args.mqtt_port,
                 "Connected to MQTT server"
             );
-            let serial = Arc::new(RwLock::new(serial));
+            let serial = Arc::new(serial);
             let mqtt_channel = Arc::new(args.mqtt_channel);
 
-            Relay::new(
-                1,
-                serial,
-                move |request: Arc<PFPRequest>| {
+            let on_event = {
+                let mqtt_channel = mqtt_channel.clone();
+                let mqtt = mqtt.clone();
+                move |event: RelayEvent| -> BoxFuture<'static, Result<()>> {
                     let mqtt_channel = mqtt_channel.clone();
                     let mqtt = mqtt.clone();
                     Box::pin(async move {
-                        if request.command_id == Command::Push {
-                            if let Ok((_, (device_serial, intensity))) =
-                                protocol_parser::parse_push_payload(&request.payload)
-                            {
-                                mqtt.write()
-                                    .await
-                                    .push(
-                                        &mqtt_channel,
-                                        &format!(
-                                            "telegraf serial={device_serial},intensity={intensity}"
-                                        ),
-                                    )
+                        let line = match event {
+                            RelayEvent::DeviceOnline { addr } => {
+                                format!("telegraf device={addr},online=true")
+                            }
+                            RelayEvent::DeviceOffline { addr } => {
+                                format!("telegraf device={addr},online=false")
+                            }
+                            RelayEvent::DeliveryFailed {
+                                dest_addr,
+                                command_id,
+                                request_id,
+                            } => format!(
+                                "telegraf device={dest_addr},undelivered_command={command_id},\
+                                 undelivered_request={request_id}"
+                            ),
+                            RelayEvent::Stats(stats) => format!("telegraf {stats}"),
+                            RelayEvent::Topology { json, dot } => {
+                                let mut mqtt = mqtt.write().await;
+                                mqtt.push_retained(&format!("{mqtt_channel}/topology"), &json)
+                                    .await?;
+                                mqtt.push_retained(&format!("{mqtt_channel}/topology/dot"), &dot)
                                     .await?;
+                                return Ok(());
                             }
+                        };
+                        mqtt.write().await.push(&mqtt_channel, &line).await?;
+                        Ok(())
+                    })
+                }
+            };
+
+            Relay::new(
+                relay_id,
+                RelayConfig {
+                    checksum: args.checksum,
+                    device_ttl: Duration::from_secs(args.device_ttl),
+                    max_hops: args.max_hops,
+                    forwarding: args.forwarding,
+                    access_list: AccessList::new(args.allow, args.deny),
+                    access_list_file: args.access_list,
+                    device_keys,
+                    device_ciphers,
+                },
+                serial,
+                move |request: Arc<PFPMessage>| {
+                    let mqtt_channel = mqtt_channel.clone();
+                    let mqtt = mqtt.clone();
+                    Box::pin(async move {
+                        if let Ok(Payload::Push {
+                            serial: device_serial,
+                            intensity,
+                            ..
+                        }) = request.decode_payload()
+                        {
+                            mqtt.write()
+                                .await
+                                .push(
+                                    &mqtt_channel,
+                                    &format!(
+                                        "telegraf serial={device_serial},intensity={intensity}"
+                                    ),
+                                )
+                                .await?;
                         }
                         Ok(())
                     })
                 },
+                on_event,
                 shutdown_signal,
             )
             .run()
@@ -197,14 +354,45 @@ async fn main() -> Result<()> {
                     .await?;
             }
         }
+        SubCommand::Topology(args) => {
+            let mut topology = Topology::new(args.relay_id);
+            let listen = tokio::time::sleep(Duration::from_secs(args.duration));
+            tokio::pin!(listen);
+
+            loop {
+                tokio::select! {
+                    _ = &mut listen => break,
+                    _ = &mut shutdown_signal => break,
+                    Ok(frame) = serial.read_frame() => {
+                        if let Ok(request) =
+                            args.checksum.open(&frame).and_then(protocol_parser::decode)
+                        {
+                            topology.observe(&request, tokio::time::Instant::now());
+                        }
+                    }
+                }
+            }
+
+            let export = topology.export(args.format, tokio::time::Instant::now());
+            println!("{}", export.trim_end());
+        }
         SubCommand::Debug => {
-            while shutdown_signal.try_recv().is_err() {
-                serial.write_buf(b"test").unwrap();
-                if let Ok(line) = serial.read_line() {
-                    debug!(
-                        "{}",
-                        String::from_utf8(line).unwrap_or_else(|_| String::new())
-                    );
+            let framing = args.serial_framing;
+            let delimiter = Delimiter::new(framing.delimiter()).strip(true);
+            let mut serial = BufReader::new(serial.into_inner());
+            loop {
+                serial.write_all(&framing.encode(b"test")).await?;
+                let mut frame = Vec::new();
+                tokio::select! {
+                    _ = &mut shutdown_signal => break,
+                    read = serial.read_until_pat(&delimiter, &mut frame) => match read {
+                        Ok(_) => debug!(
+                            frame = logger::slice_to_hex(&frame),
+                            "{}",
+                            String::from_utf8_lossy(&frame)
+                        ),
+                        Err(err) => warn!(%err, "Invalid frame"),
+                    },
                 }
             }
         }
@@ -24,14 +24,23 @@ impl SimpleMQTT {
 
     pub async fn push(&mut self, topic: &str, payload: &str) -> crate::Result<()> {
         info!(topic, payload, "Pushing to MQTT");
+        self.publish(topic, payload, false).await
+    }
+
+    /// Push a message kept by the broker for future subscribers.
+    pub async fn push_retained(&mut self, topic: &str, payload: &str) -> crate::Result<()> {
+        info!(topic, payload, "Pushing retained message to MQTT");
+        self.publish(topic, payload, true).await
+    }
 
+    async fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> crate::Result<()> {
         if !self.dry_run {
             self.client
                 .publish(
                     topic,
                     payload.as_bytes(),
                     mosquitto_rs::QoS::AtLeastOnce,
-                    false,
+                    retain,
                 )
                 .await?;
         }
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::protocol::{Command, PFPRequest};
+
+/// Delay before the first retry, doubled after each attempt.
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+/// How many times a request is sent before it is considered lost.
+pub const MAX_ATTEMPTS: u8 = 4;
+
+/// Response expected for the requests that must be acknowledged.
+///
+/// Responses echo the `request_id` of the request they answer.
+pub fn expected_response(command: Command) -> Option<Command> {
+    match command {
+        Command::Ident => Some(Command::Tnedi),
+        Command::TrustB => Some(Command::TrustT),
+        Command::Push => Some(Command::PushAck),
+        _ => None,
+    }
+}
+
+struct PendingRequest {
+    request: PFPRequest,
+    response: Command,
+    attempts: u8,
+    retry_at: Instant,
+}
+
+/// Outcome of [`Outbound::poll`].
+#[derive(Debug, Default)]
+pub struct OutboundPoll {
+    /// Requests to send again
+    pub retries: Vec<PFPRequest>,
+    /// Requests that were never acknowledged
+    pub failures: Vec<PFPRequest>,
+}
+
+/// Tracks the requests sent by the relay until they are acknowledged,
+/// keyed by `(dest_addr, request_id)`.
+pub struct Outbound {
+    pending: HashMap<(u32, u8), PendingRequest>,
+    backoff: Duration,
+    max_attempts: u8,
+}
+
+impl Outbound {
+    pub fn new(backoff: Duration, max_attempts: u8) -> Self {
+        Self {
+            pending: HashMap::new(),
+            backoff,
+            max_attempts,
+        }
+    }
+
+    /// Start tracking a request that was just sent, if it expects a response.
+    pub fn track(&mut self, request: &PFPRequest, now: Instant) {
+        let Some(response) = Command::try_from(request.command_id)
+            .ok()
+            .and_then(expected_response)
+        else {
+            return;
+        };
+
+        self.pending.insert(
+            (request.dest_addr, request.request_id),
+            PendingRequest {
+                request: request.clone(),
+                response,
+                attempts: 1,
+                retry_at: now + self.backoff,
+            },
+        );
+    }
+
+    /// Settle the request answered by a response, returns whether one was pending.
+    pub fn acknowledge(&mut self, source_addr: u32, request_id: u8, command: Command) -> bool {
+        let key = (source_addr, request_id);
+        match self.pending.get(&key) {
+            Some(pending) if pending.response == command => {
+                self.pending.remove(&key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stop tracking the requests of `commands` sent to `dest_addr`, once
+    /// superseded by a newer exchange.
+    pub fn cancel(&mut self, dest_addr: u32, commands: &[Command]) {
+        self.pending.retain(|(addr, _), pending| {
+            *addr != dest_addr
+                || !Command::try_from(pending.request.command_id)
+                    .is_ok_and(|command| commands.contains(&command))
+        });
+    }
+
+    /// Requests due for a retry, and the ones that ran out of attempts.
+    pub fn poll(&mut self, now: Instant) -> OutboundPoll {
+        let mut poll = OutboundPoll::default();
+        let max_attempts = self.max_attempts;
+        let backoff = self.backoff;
+
+        self.pending.retain(|_, pending| {
+            if now < pending.retry_at {
+                return true;
+            }
+            if pending.attempts >= max_attempts {
+                poll.failures.push(pending.request.clone());
+                return false;
+            }
+
+            pending.retry_at = now + backoff * 2u32.pow(pending.attempts as u32);
+            pending.attempts += 1;
+            poll.retries.push(pending.request.clone());
+            true
+        });
+
+        poll
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    protocol::{wire_u32, Command, ProtocolError, PAYLOAD_SIZE},
+    protocol_parser,
+};
+
+/// Decoded content of a request, one variant per [`Command`].
+///
+/// Payloads are zero padded to [`PAYLOAD_SIZE`], the variants with fields
+/// use the following layouts:
+///
+/// | Command | Offset | Size | Field       |
+/// |---------|--------|------|-------------|
+/// | `Tnedi` | 0      | 4    | `serial`    |
+/// | `Push`  | 0      | 4    | `serial`    |
+/// | `Push`  | 4      | 4    | `intensity` |
+/// | `Push`  | 8      | 4    | `sequence`  |
+///
+/// The `sequence` of `Push` was added in wire version 2, older devices leave
+/// it at 0.
+/// | `Add`   | 0      | 4    | `addr`      |
+/// | `Del`   | 0      | 4    | `addr`      |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    HeloP,
+    OlehP,
+    Ident,
+    Tnedi {
+        serial: u32,
+    },
+    TrustB,
+    TrustT,
+    HeloL,
+    OlehL,
+    /// `sequence` keeps growing across restarts of the device, see
+    /// [`crate::replay`]
+    Push {
+        serial: u32,
+        intensity: u32,
+        sequence: u32,
+    },
+    PushAck,
+    ADeny,
+    UDeny,
+    Add {
+        addr: u32,
+    },
+    Del {
+        addr: u32,
+    },
+    Alive,
+}
+
+impl Payload {
+    pub fn command(&self) -> Command {
+        match self {
+            Payload::HeloP => Command::HeloP,
+            Payload::OlehP => Command::OlehP,
+            Payload::Ident => Command::Ident,
+            Payload::Tnedi { .. } => Command::Tnedi,
+            Payload::TrustB => Command::TrustB,
+            Payload::TrustT => Command::TrustT,
+            Payload::HeloL => Command::HeloL,
+            Payload::OlehL => Command::OlehL,
+            Payload::Push { .. } => Command::Push,
+            Payload::PushAck => Command::PushAck,
+            Payload::ADeny => Command::ADeny,
+            Payload::UDeny => Command::UDeny,
+            Payload::Add { .. } => Command::Add,
+            Payload::Del { .. } => Command::Del,
+            Payload::Alive => Command::Alive,
+        }
+    }
+
+    /// Encode the payload, zero padded to [`PAYLOAD_SIZE`].
+    pub fn encode(&self) -> [u8; PAYLOAD_SIZE] {
+        let mut payload = [0; PAYLOAD_SIZE];
+        match self {
+            Payload::Tnedi { serial } => payload[0..4].copy_from_slice(&wire_u32(*serial)),
+            Payload::Push {
+                serial,
+                intensity,
+                sequence,
+            } => {
+                payload[0..4].copy_from_slice(&wire_u32(*serial));
+                payload[4..8].copy_from_slice(&wire_u32(*intensity));
+                payload[8..12].copy_from_slice(&wire_u32(*sequence));
+            }
+            Payload::Add { addr } | Payload::Del { addr } => {
+                payload[0..4].copy_from_slice(&wire_u32(*addr))
+            }
+            _ => (),
+        }
+        payload
+    }
+
+    pub fn decode(command: Command, input: &[u8]) -> Result<Self, ProtocolError> {
+        let malformed = |_| ProtocolError::Malformed;
+        Ok(match command {
+            Command::HeloP => Payload::HeloP,
+            Command::OlehP => Payload::OlehP,
+            Command::Ident => Payload::Ident,
+            Command::Tnedi => {
+                let (_, serial) = protocol_parser::parse_tnedi_payload(input).map_err(malformed)?;
+                Payload::Tnedi { serial }
+            }
+            Command::TrustB => Payload::TrustB,
+            Command::TrustT => Payload::TrustT,
+            Command::HeloL => Payload::HeloL,
+            Command::OlehL => Payload::OlehL,
+            Command::Push => {
+                let (_, (serial, intensity, sequence)) =
+                    protocol_parser::parse_push_payload(input).map_err(malformed)?;
+                Payload::Push {
+                    serial,
+                    intensity,
+                    sequence,
+                }
+            }
+            Command::PushAck => Payload::PushAck,
+            Command::ADeny => Payload::ADeny,
+            Command::UDeny => Payload::UDeny,
+            Command::Add => {
+                let (_, addr) = protocol_parser::parse_addr_payload(input).map_err(malformed)?;
+                Payload::Add { addr }
+            }
+            Command::Del => {
+                let (_, addr) = protocol_parser::parse_addr_payload(input).map_err(malformed)?;
+                Payload::Del { addr }
+            }
+            Command::Alive => Payload::Alive,
+        })
+    }
+}
@@ -1,8 +1,98 @@
-use tracing::warn;
+//! PFP wire format, version [`WIRE_VERSION`].
+//!
+//! Every multi-byte integer is big-endian, in the header as well as in the
+//! payloads, and must be written with [`wire_u16`] / [`wire_u32`] and read
+//! back with their `protocol_parser` counterparts. A packet is a fixed size
+//! header followed by the payload, then by the optional trailers:
+//!
+//! | Offset | Size | Field               |
+//! |--------|------|---------------------|
+//! | 0      | 1    | `command_id`        |
+//! | 1      | 1    | `hop_count`         |
+//! | 2      | 4    | `source_addr`       |
+//! | 6      | 4    | `dest_addr`         |
+//! | 10     | 4    | `forwarded_by_addr` |
+//! | 14     | 1    | `request_id`        |
+//! | 15     | 1    | `request_part`      |
+//! | 16     | 1    | `request_count`     |
+//! | 17     | 32   | `payload`           |
+//! | 49     | 28   | nonce and tag       |
+//! | 49/77  | 8    | MAC                 |
+//! | end    | 2/4  | checksum            |
+//!
+//! The nonce and tag are only present for the devices with an encryption key,
+//! see [`crate::encryption`], and the MAC for the devices with an
+//! authentication key, see [`crate::auth`]. The checksum selected with
+//! [`Checksum`](crate::checksum::Checksum) covers everything before it.
+//!
+//! The layout of each payload is described on [`Payload`].
 
+use std::fmt;
+
+use crate::payload::Payload;
+
+/// Version of the wire format described in this module.
+///
+/// Version 2 added the `sequence` of `Push`, see [`Payload`].
+pub const WIRE_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 17;
 pub const PAYLOAD_SIZE: usize = 32;
+pub const PACKET_SIZE: usize = HEADER_SIZE + PAYLOAD_SIZE;
+/// Destination of the requests meant for every node.
+pub const BROADCAST_ADDR: u32 = 0x0000_0000;
+/// Destination of the requests meant for whichever relay receives them,
+/// used by devices that do not know the id of their relay yet.
+pub const RELAY_ADDR: u32 = 0xFFFF_FFFF;
+/// Whether `addr` can be the address of a device, reserved addresses cannot.
+pub fn is_device_addr(addr: u32) -> bool {
+    addr != BROADCAST_ADDR && addr != RELAY_ADDR
+}
+
+/// Size of the length prefix of multi-part messages.
+pub const MESSAGE_LENGTH_SIZE: usize = 2;
+/// Largest payload that can be split over `u8::MAX` parts.
+pub const MAX_MESSAGE_SIZE: usize = u8::MAX as usize * PAYLOAD_SIZE - MESSAGE_LENGTH_SIZE;
+
+/// Encode a `u16` in the wire byte order.
+pub fn wire_u16(value: u16) -> [u8; 2] {
+    value.to_be_bytes()
+}
 
-#[derive(Debug, Clone, Copy)]
+/// Encode a `u32` in the wire byte order.
+pub fn wire_u32(value: u32) -> [u8; 4] {
+    value.to_be_bytes()
+}
+
+/// Errors raised while decoding a PFP frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The command byte does not map to any known [`Command`].
+    UnknownCommand(u8),
+    /// The frame could not be parsed as a PFP request.
+    Malformed,
+    /// The payload does not fit in the maximum number of parts.
+    PayloadTooLarge(usize),
+    /// The trailing checksum does not match the frame content.
+    ChecksumMismatch,
+    /// The request from a trusted device has a missing or invalid MAC.
+    Unauthenticated,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownCommand(value) => write!(f, "unknown command: 0x{value:02x}"),
+            ProtocolError::Malformed => write!(f, "malformed request"),
+            ProtocolError::PayloadTooLarge(size) => write!(f, "payload too large: {size} bytes"),
+            ProtocolError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            ProtocolError::Unauthenticated => write!(f, "unauthenticated request"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     HeloP,
     OlehP,
@@ -43,9 +133,11 @@ impl From<Command> for u8 {
     }
 }
 
-impl From<u8> for Command {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for Command {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
             0x00 => Command::HeloP,
             0x01 => Command::OlehP,
             0x02 => Command::Ident,
@@ -61,11 +153,8 @@ impl From<u8> for Command {
             0x0C => Command::Add,
             0x0D => Command::Del,
             0x0E => Command::Alive,
-            _ => {
-                warn!("Unknown command: {}", value);
-                panic!("Unknown command: {value}");
-            }
-        }
+            _ => return Err(ProtocolError::UnknownCommand(value)),
+        })
     }
 }
 
@@ -75,7 +164,7 @@ impl PartialEq<Command> for u8 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PFPRequest {
     pub command_id: u8,
     pub hop_count: u8,
@@ -90,12 +179,12 @@ pub struct PFPRequest {
 
 impl From<PFPRequest> for Vec<u8> {
     fn from(value: PFPRequest) -> Self {
-        let mut result = Vec::new();
+        let mut result = Vec::with_capacity(PACKET_SIZE);
         result.push(value.command_id);
         result.push(value.hop_count);
-        result.extend_from_slice(&value.source_addr.to_be_bytes());
-        result.extend_from_slice(&value.dest_addr.to_be_bytes());
-        result.extend_from_slice(&value.forwarded_by_addr.to_be_bytes());
+        result.extend_from_slice(&wire_u32(value.source_addr));
+        result.extend_from_slice(&wire_u32(value.dest_addr));
+        result.extend_from_slice(&wire_u32(value.forwarded_by_addr));
         result.push(value.request_id);
         result.push(value.request_part);
         result.push(value.request_count);
@@ -104,52 +193,262 @@ impl From<PFPRequest> for Vec<u8> {
     }
 }
 
-impl PFPRequest {
-    pub fn new_helop(source_addr: u32) -> Self {
+/// A logical message, made of one or more [`PFPRequest`] parts.
+///
+/// Single part messages keep their raw payload. Multi-part messages start
+/// with their length as a `u16` (see [`MESSAGE_LENGTH_SIZE`]) so
+/// that the padding of the last part can be dropped once reassembled.
+#[derive(Debug)]
+pub struct PFPMessage {
+    pub command_id: u8,
+    pub hop_count: u8,
+    pub source_addr: u32,
+    pub dest_addr: u32,
+    pub forwarded_by_addr: u32,
+    pub request_id: u8,
+    pub payload: Vec<u8>,
+}
+
+impl From<PFPRequest> for PFPMessage {
+    fn from(value: PFPRequest) -> Self {
         Self {
-            command_id: Command::HeloP.into(),
-            hop_count: 0,
-            source_addr,
-            dest_addr: 0,
-            forwarded_by_addr: 0,
-            request_id: 1,
-            request_part: 0,
-            request_count: 1,
-            payload: [0; PAYLOAD_SIZE],
+            command_id: value.command_id,
+            hop_count: value.hop_count,
+            source_addr: value.source_addr,
+            dest_addr: value.dest_addr,
+            forwarded_by_addr: value.forwarded_by_addr,
+            request_id: value.request_id,
+            payload: value.payload.to_vec(),
         }
     }
+}
 
-    pub fn new_add(source_addr: u32, new_device_addr: u32) -> Self {
-        let mut payload = [0; PAYLOAD_SIZE];
-        payload[0..4].copy_from_slice(&new_device_addr.to_le_bytes());
+impl PFPMessage {
+    pub fn decode_payload(&self) -> Result<Payload, ProtocolError> {
+        Payload::decode(Command::try_from(self.command_id)?, &self.payload)
+    }
+}
 
-        Self {
-            command_id: Command::Add.into(),
-            hop_count: 0,
-            source_addr,
-            dest_addr: 0,
-            forwarded_by_addr: 0,
-            request_id: 1,
-            request_part: 0,
-            request_count: 1,
-            payload,
+impl PFPRequest {
+    pub fn is_broadcast(&self) -> bool {
+        self.dest_addr == BROADCAST_ADDR
+    }
+
+    /// Start a single part broadcast request, with a `request_id` of 0.
+    pub fn builder(command: Command, source_addr: u32) -> PFPRequestBuilder {
+        PFPRequestBuilder {
+            request: Self {
+                command_id: command.into(),
+                hop_count: 0,
+                source_addr,
+                dest_addr: BROADCAST_ADDR,
+                forwarded_by_addr: 0,
+                request_id: 0,
+                request_part: 0,
+                request_count: 1,
+                payload: [0; PAYLOAD_SIZE],
+            },
         }
     }
 
-    pub fn new_del(source_addr: u32, lost_device_addr: u32) -> Self {
-        let mut payload = [0; PAYLOAD_SIZE];
-        payload[0..4].copy_from_slice(&lost_device_addr.to_le_bytes());
+    /// Start a request carrying a typed `payload`.
+    pub fn with_payload(source_addr: u32, payload: &Payload) -> PFPRequestBuilder {
+        Self::builder(payload.command(), source_addr).payload(payload.encode())
+    }
 
-        Self {
-            command_id: Command::Add.into(),
-            hop_count: 0,
+    pub fn new_helop(source_addr: u32, request_id: u8) -> Self {
+        Self::with_payload(source_addr, &Payload::HeloP)
+            .request_id(request_id)
+            .build()
+    }
+
+    pub fn new_add(source_addr: u32, request_id: u8, new_device_addr: u32) -> Self {
+        Self::with_payload(
+            source_addr,
+            &Payload::Add {
+                addr: new_device_addr,
+            },
+        )
+        .request_id(request_id)
+        .build()
+    }
+
+    pub fn new_del(source_addr: u32, request_id: u8, lost_device_addr: u32) -> Self {
+        Self::with_payload(
             source_addr,
-            dest_addr: 0,
-            forwarded_by_addr: 0,
-            request_id: 1,
-            request_part: 0,
-            request_count: 1,
-            payload,
+            &Payload::Del {
+                addr: lost_device_addr,
+            },
+        )
+        .request_id(request_id)
+        .build()
+    }
+
+    /// Split `data` into as many parts as needed, all sharing `request_id`.
+    ///
+    /// Payloads that fit in a single part are sent as is, larger ones are
+    /// prefixed by their length as described on [`PFPMessage`].
+    pub fn fragment(
+        command: Command,
+        source_addr: u32,
+        dest_addr: u32,
+        request_id: u8,
+        data: &[u8],
+    ) -> Result<Vec<Self>, ProtocolError> {
+        let request = |request_part: u8, request_count: u8, chunk: &[u8]| {
+            let mut payload = [0; PAYLOAD_SIZE];
+            payload[..chunk.len()].copy_from_slice(chunk);
+            Self::builder(command, source_addr)
+                .dest_addr(dest_addr)
+                .request_id(request_id)
+                .part(request_part, request_count)
+                .payload(payload)
+                .build()
+        };
+
+        if data.len() <= PAYLOAD_SIZE {
+            return Ok(vec![request(0, 1, data)]);
+        }
+        if data.len() > MAX_MESSAGE_SIZE {
+            return Err(ProtocolError::PayloadTooLarge(data.len()));
+        }
+
+        let mut message = Vec::with_capacity(MESSAGE_LENGTH_SIZE + data.len());
+        message.extend_from_slice(&wire_u16(data.len() as u16));
+        message.extend_from_slice(data);
+
+        let chunks = message.chunks(PAYLOAD_SIZE);
+        let request_count = chunks.len() as u8;
+        Ok(chunks
+            .enumerate()
+            .map(|(request_part, chunk)| request(request_part as u8, request_count, chunk))
+            .collect())
+    }
+}
+
+/// Builder for [`PFPRequest`], see [`PFPRequest::builder`].
+#[derive(Debug, Clone)]
+pub struct PFPRequestBuilder {
+    request: PFPRequest,
+}
+
+impl PFPRequestBuilder {
+    pub fn dest_addr(mut self, dest_addr: u32) -> Self {
+        self.request.dest_addr = dest_addr;
+        self
+    }
+
+    pub fn request_id(mut self, request_id: u8) -> Self {
+        self.request.request_id = request_id;
+        self
+    }
+
+    pub fn part(mut self, request_part: u8, request_count: u8) -> Self {
+        self.request.request_part = request_part;
+        self.request.request_count = request_count;
+        self
+    }
+
+    pub fn payload(mut self, payload: [u8; PAYLOAD_SIZE]) -> Self {
+        self.request.payload = payload;
+        self
+    }
+
+    pub fn build(self) -> PFPRequest {
+        self.request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_parser;
+
+    fn payloads() -> Vec<Payload> {
+        vec![
+            Payload::HeloP,
+            Payload::OlehP,
+            Payload::Ident,
+            Payload::Tnedi {
+                serial: 0x1234_5678,
+            },
+            Payload::TrustB,
+            Payload::TrustT,
+            Payload::HeloL,
+            Payload::OlehL,
+            Payload::Push {
+                serial: 0xDEAD_BEEF,
+                intensity: 0x0D0A_0D0A,
+                sequence: 0x0001_0203,
+            },
+            Payload::PushAck,
+            Payload::ADeny,
+            Payload::UDeny,
+            Payload::Add { addr: 0x0102_0304 },
+            Payload::Del { addr: 0xA1B2_C3D4 },
+            Payload::Alive,
+        ]
+    }
+
+    #[test]
+    fn payloads_cover_every_command() {
+        let commands = payloads()
+            .iter()
+            .map(|payload| u8::from(payload.command()))
+            .collect::<Vec<_>>();
+        let expected = (0..=u8::from(Command::Alive)).collect::<Vec<_>>();
+        assert_eq!(commands, expected);
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for payload in payloads() {
+            let mut request = PFPRequest::with_payload(0x1122_3344, &payload)
+                .dest_addr(0x5566_7788)
+                .request_id(42)
+                .build();
+            request.hop_count = 3;
+            request.forwarded_by_addr = 0x99AA_BBCC;
+
+            let bytes = Vec::from(request.clone());
+            assert_eq!(bytes.len(), PACKET_SIZE);
+
+            let decoded = protocol_parser::decode(&bytes).unwrap();
+            assert_eq!(decoded, request);
+            assert_eq!(Vec::from(decoded.clone()), bytes);
+
+            let message = PFPMessage::from(decoded);
+            assert_eq!(message.decode_payload().unwrap(), payload);
+        }
+    }
+
+    #[test]
+    fn header_is_big_endian() {
+        let bytes = Vec::from(PFPRequest::new_add(0x0102_0304, 1, 0x0A0B_0C0D));
+        assert_eq!(bytes[2..6], [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(
+            bytes[HEADER_SIZE..HEADER_SIZE + 4],
+            [0x0A, 0x0B, 0x0C, 0x0D]
+        );
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        let data = (0..=200).collect::<Vec<u8>>();
+        let requests = PFPRequest::fragment(Command::Push, 1, 2, 7, &data).unwrap();
+        assert_eq!(requests.len(), 7);
+
+        let mut reassembled = Vec::new();
+        for (request_part, request) in requests.into_iter().enumerate() {
+            assert_eq!(request.request_part as usize, request_part);
+            let decoded = protocol_parser::decode(&Vec::from(request.clone())).unwrap();
+            assert_eq!(decoded, request);
+            reassembled.extend_from_slice(&decoded.payload);
         }
+        let (_, length) = protocol_parser::parse_message_length(&reassembled).unwrap();
+        assert_eq!(
+            reassembled[MESSAGE_LENGTH_SIZE..MESSAGE_LENGTH_SIZE + length as usize],
+            data
+        );
     }
 }
@@ -1,25 +1,32 @@
-use crate::protocol::{Command, PFPRequest, PAYLOAD_SIZE};
+use crate::protocol::{Command, PFPRequest, ProtocolError, PAYLOAD_SIZE};
 use nom::{
     bytes,
-    combinator::verify,
-    number::complete::{be_u32, be_u8},
+    number::complete::{be_u16, be_u32, be_u8},
     sequence::tuple,
     IResult,
 };
 
+/// Read a `u16` in the wire byte order, see [`crate::protocol`].
+pub fn wire_u16(input: &[u8]) -> IResult<&[u8], u16> {
+    be_u16(input)
+}
+
+/// Read a `u32` in the wire byte order, see [`crate::protocol`].
+pub fn wire_u32(input: &[u8]) -> IResult<&[u8], u32> {
+    be_u32(input)
+}
+
 fn parse_payload(input: &[u8]) -> IResult<&[u8], [u8; PAYLOAD_SIZE]> {
     let (input, payload) = bytes::complete::take(PAYLOAD_SIZE)(input)?;
     Ok((input, payload.try_into().unwrap()))
 }
 
 pub fn parse(input: &[u8]) -> IResult<&[u8], PFPRequest> {
-    let u32_parser = be_u32;
+    let u32_parser = wire_u32;
     let u8_parser = be_u8;
 
     let (input, result) = tuple((
-        verify(u8_parser, |byte: &u8| {
-            *byte >= u8::from(Command::HeloP) && *byte <= u8::from(Command::Alive)
-        }),
+        u8_parser,
         u8_parser,
         u32_parser,
         u32_parser,
@@ -46,7 +53,25 @@ pub fn parse(input: &[u8]) -> IResult<&[u8], PFPRequest> {
     ))
 }
 
-pub fn parse_push_payload(input: &[u8]) -> IResult<&[u8], (u32, u32)> {
-    let (input, result) = tuple((be_u32, be_u32))(input)?;
-    Ok((input, (result.0, result.1)))
+/// Parse a full frame and make sure its command byte is a known [`Command`].
+pub fn decode(input: &[u8]) -> Result<PFPRequest, ProtocolError> {
+    let (_, request) = parse(input).map_err(|_| ProtocolError::Malformed)?;
+    Command::try_from(request.command_id)?;
+    Ok(request)
+}
+
+pub fn parse_push_payload(input: &[u8]) -> IResult<&[u8], (u32, u32, u32)> {
+    tuple((wire_u32, wire_u32, wire_u32))(input)
+}
+
+pub fn parse_tnedi_payload(input: &[u8]) -> IResult<&[u8], u32> {
+    wire_u32(input)
+}
+
+pub fn parse_addr_payload(input: &[u8]) -> IResult<&[u8], u32> {
+    wire_u32(input)
+}
+
+pub fn parse_message_length(input: &[u8]) -> IResult<&[u8], u16> {
+    wire_u16(input)
 }
@@ -1,46 +1,360 @@
-use std::io::{BufRead, ErrorKind};
+use std::{
+    future::Future,
+    io::{self, BufRead, ErrorKind},
+};
 
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Frames longer than this are rejected by default.
+pub const MAX_FRAME_LEN: usize = 4096;
+
+/// Pattern ending a frame, and how frames are read up to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Delimiter<'a> {
+    pattern: &'a [u8],
+    max_frame_len: usize,
+    strip: bool,
+}
+
+/// Outcome of [`Delimiter::scan`].
+enum Scan {
+    /// The delimiter was found, the frame is complete
+    Complete,
+    /// More bytes are needed
+    Partial,
+    /// The frame grew past the maximum length
+    TooLong,
+}
+
+impl<'a> Delimiter<'a> {
+    /// Frames ending with `pattern`, kept in the frame.
+    pub fn new(pattern: &'a [u8]) -> Self {
+        assert!(!pattern.is_empty(), "empty delimiter");
+        Self {
+            pattern,
+            max_frame_len: MAX_FRAME_LEN,
+            strip: false,
+        }
+    }
+
+    /// Longest frame accepted, delimiter excluded.
+    pub fn max_frame_len(mut self, max_frame_len: usize) -> Self {
+        self.max_frame_len = max_frame_len;
+        self
+    }
+
+    /// Remove the delimiter from the frames.
+    pub fn strip(mut self, strip: bool) -> Self {
+        self.strip = strip;
+        self
+    }
+
+    /// Append `available` to the frame started at `buf[start..]`, stopping
+    /// right after the delimiter.
+    ///
+    /// The search resumes a few bytes before the end of the previous chunk so
+    /// that a delimiter split between two chunks is found. A frame over the
+    /// maximum length is cut right after it, whatever the size of the chunks.
+    /// Returns the number of bytes of `available` used.
+    fn scan(&self, buf: &mut Vec<u8>, start: usize, available: &[u8]) -> (usize, Scan) {
+        let from = buf.len() - (buf.len() - start).min(self.pattern.len() - 1);
+        let before = buf.len();
+        buf.extend_from_slice(available);
+
+        let found = buf[from..]
+            .windows(self.pattern.len())
+            .position(|window| window == self.pattern)
+            .map(|position| from + position);
+        // The end of the buffer might be the start of a split delimiter
+        let frame_end = found.unwrap_or_else(|| {
+            (buf.len() + 1)
+                .saturating_sub(self.pattern.len())
+                .max(start)
+        });
+        if frame_end - start > self.max_frame_len {
+            // What looked like the start of a delimiter may already be used
+            let cut = (start + self.max_frame_len + 1).max(before);
+            buf.truncate(cut);
+            return (cut - before, Scan::TooLong);
+        }
+
+        match found {
+            Some(frame_end) => {
+                let end = frame_end + self.pattern.len();
+                buf.truncate(if self.strip { frame_end } else { end });
+                (end - before, Scan::Complete)
+            }
+            None => (available.len(), Scan::Partial),
+        }
+    }
+
+    /// Skip `available` up to and including the delimiter, `skipped` holding
+    /// the bytes skipped so far.
+    ///
+    /// Only the last bytes of `skipped` are kept, in case the delimiter is
+    /// split between chunks. Returns the number of bytes of `available` used.
+    fn skip(&self, skipped: &mut Vec<u8>, available: &[u8]) -> (usize, Scan) {
+        let unbounded = Self {
+            max_frame_len: usize::MAX,
+            ..*self
+        };
+        let (used, scan) = unbounded.scan(skipped, 0, available);
+        skipped.drain(..skipped.len().saturating_sub(self.pattern.len() - 1));
+        (used, scan)
+    }
+
+    fn too_long(&self) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("frame longer than {} bytes", self.max_frame_len),
+        )
+    }
+}
+
+/// Read a frame into `buf`, up to and including the delimiter unless it is
+/// stripped.
+///
+/// Returns the number of bytes consumed from the reader, 0 at the end of the
+/// stream. A frame cut by the end of the stream fails with
+/// [`ErrorKind::UnexpectedEof`], the bytes read so far being consumed.
+///
+/// A frame over the maximum length fails with [`ErrorKind::InvalidData`] once
+/// skipped up to and including its delimiter, or up to the end of the stream,
+/// so that the next read starts with the next frame. Nothing of it is left in
+/// `buf`.
 pub trait ReadUntilPat {
-    fn read_until_pat<const L: usize>(
+    fn read_until_pat(&mut self, delimiter: &Delimiter, buf: &mut Vec<u8>) -> io::Result<usize>;
+}
+
+impl<R: BufRead + ?Sized> ReadUntilPat for R {
+    fn read_until_pat(&mut self, delimiter: &Delimiter, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        let mut read = 0;
+        let mut skipped = None;
+        loop {
+            let (used, scan) = {
+                let available = match self.fill_buf() {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match (&mut skipped, available.is_empty()) {
+                    (Some(_), true) => return Err(delimiter.too_long()),
+                    (None, true) if read == 0 => return Ok(0),
+                    (None, true) => return Err(ErrorKind::UnexpectedEof.into()),
+                    (Some(skipped), false) => delimiter.skip(skipped, available),
+                    (None, false) => delimiter.scan(buf, start, available),
+                }
+            };
+            self.consume(used);
+            read += used;
+            match scan {
+                Scan::Complete if skipped.is_some() => return Err(delimiter.too_long()),
+                Scan::Complete => return Ok(read),
+                Scan::TooLong => skipped = Some(buf.split_off(start)),
+                Scan::Partial => (),
+            }
+        }
+    }
+}
+
+/// Asynchronous [`ReadUntilPat`].
+pub trait AsyncReadUntilPat {
+    fn read_until_pat(
         &mut self,
-        delim: &[u8; L],
+        delimiter: &Delimiter,
         buf: &mut Vec<u8>,
-    ) -> std::io::Result<usize>;
+    ) -> impl Future<Output = io::Result<usize>>;
 }
 
-impl<R: BufRead + ?Sized> ReadUntilPat for R {
-    fn read_until_pat<const L: usize>(
+impl<R: AsyncBufRead + Unpin + ?Sized> AsyncReadUntilPat for R {
+    async fn read_until_pat(
         &mut self,
-        delim: &[u8; L],
+        delimiter: &Delimiter<'_>,
         buf: &mut Vec<u8>,
-    ) -> std::io::Result<usize> {
+    ) -> io::Result<usize> {
+        let start = buf.len();
         let mut read = 0;
+        let mut skipped = None;
         loop {
-            let (done, used) = {
-                let available = match self.fill_buf() {
+            let (used, scan) = {
+                let available = match self.fill_buf().await {
                     Ok(n) => n,
                     Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
                     Err(e) => return Err(e),
                 };
-                match available
-                    .windows(delim.len())
-                    .position(|window| window == delim)
-                {
-                    Some(i) => {
-                        buf.extend_from_slice(&available[..=i]);
-                        (true, i + 1)
-                    }
-                    None => {
-                        buf.extend_from_slice(available);
-                        (false, available.len())
-                    }
+                match (&mut skipped, available.is_empty()) {
+                    (Some(_), true) => return Err(delimiter.too_long()),
+                    (None, true) if read == 0 => return Ok(0),
+                    (None, true) => return Err(ErrorKind::UnexpectedEof.into()),
+                    (Some(skipped), false) => delimiter.skip(skipped, available),
+                    (None, false) => delimiter.scan(buf, start, available),
                 }
             };
             self.consume(used);
             read += used;
-            if done || used == 0 {
-                return Ok(read);
+            match scan {
+                Scan::Complete if skipped.is_some() => return Err(delimiter.too_long()),
+                Scan::Complete => return Ok(read),
+                Scan::TooLong => skipped = Some(buf.split_off(start)),
+                Scan::Partial => (),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+
+    /// Read every frame of `data`, fed `chunk` bytes at a time.
+    fn read_all(data: &[u8], chunk: usize, delimiter: &Delimiter) -> Vec<io::Result<Vec<u8>>> {
+        let mut reader = BufReader::with_capacity(chunk, data);
+        let mut frames = Vec::new();
+        loop {
+            let mut frame = Vec::new();
+            match reader.read_until_pat(delimiter, &mut frame) {
+                Ok(0) => return frames,
+                Ok(_) => frames.push(Ok(frame)),
+                Err(err) => frames.push(Err(err)),
+            }
+        }
+    }
+
+    fn kinds(frames: Vec<io::Result<Vec<u8>>>) -> Vec<Result<Vec<u8>, ErrorKind>> {
+        frames
+            .into_iter()
+            .map(|frame| frame.map_err(|err| err.kind()))
+            .collect()
+    }
+
+    #[test]
+    fn split_delimiters() {
+        let delimiter = Delimiter::new(b"\r\n");
+        for chunk in 1..=8 {
+            assert_eq!(
+                kinds(read_all(b"ab\r\n\rc\n\r\n\r\n", chunk, &delimiter)),
+                [
+                    Ok(b"ab\r\n".to_vec()),
+                    Ok(b"\rc\n\r\n".to_vec()),
+                    Ok(b"\r\n".to_vec())
+                ],
+                "chunks of {chunk}"
+            );
+            assert_eq!(
+                kinds(read_all(
+                    b"abcENDEENDENENDEND",
+                    chunk,
+                    &Delimiter::new(b"END")
+                )),
+                [
+                    Ok(b"abcEND".to_vec()),
+                    Ok(b"EEND".to_vec()),
+                    Ok(b"ENEND".to_vec()),
+                    Ok(b"END".to_vec())
+                ],
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn strip() {
+        let delimiter = Delimiter::new(b"\r\n").strip(true);
+        for chunk in 1..=4 {
+            assert_eq!(
+                kinds(read_all(b"ab\r\n\r\ncd\r\n", chunk, &delimiter)),
+                [Ok(b"ab".to_vec()), Ok(vec![]), Ok(b"cd".to_vec())],
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn longest_frame() {
+        let delimiter = Delimiter::new(b"\r\n").max_frame_len(4).strip(true);
+        for chunk in 1..=8 {
+            assert_eq!(
+                kinds(read_all(b"abcd\r\n", chunk, &delimiter)),
+                [Ok(b"abcd".to_vec())],
+                "chunks of {chunk}"
+            );
+            let delimiter = Delimiter::new(b"END").max_frame_len(4).strip(true);
+            assert_eq!(
+                kinds(read_all(b"abcdEND", chunk, &delimiter)),
+                [Ok(b"abcd".to_vec())],
+                "chunks of {chunk}"
+            );
+        }
+    }
+
+    #[test]
+    fn overflow_skips_to_the_next_frame() {
+        let delimiter = Delimiter::new(b"\r\n").max_frame_len(4).strip(true);
+        for chunk in 1..=16 {
+            assert_eq!(
+                kinds(read_all(b"abcde\rfgh\r\nok\r\n", chunk, &delimiter)),
+                [Err(ErrorKind::InvalidData), Ok(b"ok".to_vec())],
+                "chunks of {chunk}"
+            );
+            let delimiter = Delimiter::new(b"END").max_frame_len(4).strip(true);
+            for data in [&b"abcdeENfgENDokEND"[..], b"abcdENENDokEND"] {
+                assert_eq!(
+                    kinds(read_all(data, chunk, &delimiter)),
+                    [Err(ErrorKind::InvalidData), Ok(b"ok".to_vec())],
+                    "chunks of {chunk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn overflow_keeps_nothing_of_the_frame() {
+        let delimiter = Delimiter::new(&[0]).max_frame_len(2);
+        let mut reader = BufReader::with_capacity(2, &b"xyabc\0"[..]);
+        let mut buf = b"x".to_vec();
+        assert_eq!(
+            reader
+                .read_until_pat(&delimiter, &mut buf)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(buf, b"x");
+        assert_eq!(reader.read_until_pat(&delimiter, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn end_of_stream() {
+        let delimiter = Delimiter::new(&[0]).max_frame_len(4);
+        assert_eq!(
+            kinds(read_all(b"ab\0cd", 1, &delimiter)),
+            [Ok(b"ab\0".to_vec()), Err(ErrorKind::UnexpectedEof)]
+        );
+        assert_eq!(
+            kinds(read_all(b"abcdefgh", 3, &delimiter)),
+            [Err(ErrorKind::InvalidData)]
+        );
+        assert!(read_all(b"", 1, &delimiter).is_empty());
+    }
+
+    #[tokio::test]
+    async fn async_reader() {
+        let delimiter = Delimiter::new(b"\r\n").max_frame_len(4).strip(true);
+        let mut reader = tokio::io::BufReader::with_capacity(1, &b"abcdef\r\nok\r\n"[..]);
+        let mut frame = Vec::new();
+        let err = AsyncReadUntilPat::read_until_pat(&mut reader, &delimiter, &mut frame)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            AsyncReadUntilPat::read_until_pat(&mut reader, &delimiter, &mut frame)
+                .await
+                .unwrap(),
+            4
+        );
+        assert_eq!(frame, b"ok");
+    }
+}
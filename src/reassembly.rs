@@ -0,0 +1,283 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    protocol::{PFPMessage, PFPRequest, MESSAGE_LENGTH_SIZE, PAYLOAD_SIZE},
+    protocol_parser,
+};
+
+/// How long the parts of an incomplete message are kept around.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Maximum number of messages being reassembled at the same time.
+const MAX_PENDING: usize = 64;
+
+/// Result of feeding a part to the [`Reassembler`].
+#[derive(Debug)]
+pub enum Reassembly {
+    /// Every part was received.
+    Complete(PFPMessage),
+    /// More parts are needed.
+    Pending,
+    /// This part was already received.
+    Duplicate,
+    /// The part index or count does not make sense.
+    Invalid,
+}
+
+struct PendingMessage {
+    first: PFPRequest,
+    parts: Vec<Option<[u8; PAYLOAD_SIZE]>>,
+    received: usize,
+    started_at: Instant,
+}
+
+/// Stitches multi-part requests back together, keyed by `(source_addr, request_id)`.
+pub struct Reassembler {
+    pending: HashMap<(u32, u8), PendingMessage>,
+    timeout: Duration,
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn push(&mut self, request: PFPRequest, now: Instant) -> Reassembly {
+        if request.request_count <= 1 {
+            return if request.request_part == 0 {
+                Reassembly::Complete(request.into())
+            } else {
+                Reassembly::Invalid
+            };
+        }
+        if request.request_part >= request.request_count {
+            return Reassembly::Invalid;
+        }
+
+        let key = (request.source_addr, request.request_id);
+        if let Some(pending) = self.pending.get(&key) {
+            // Same id but another shape: the sender moved on to a new message
+            if pending.first.request_count != request.request_count
+                || pending.first.command_id != request.command_id
+            {
+                self.pending.remove(&key);
+            }
+        }
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            self.evict_oldest();
+        }
+
+        let pending = self.pending.entry(key).or_insert_with(|| PendingMessage {
+            first: request.clone(),
+            parts: vec![None; request.request_count as usize],
+            received: 0,
+            started_at: now,
+        });
+        let slot = &mut pending.parts[request.request_part as usize];
+        if slot.is_some() {
+            return Reassembly::Duplicate;
+        }
+        *slot = Some(request.payload);
+        pending.received += 1;
+
+        if pending.received < pending.parts.len() {
+            return Reassembly::Pending;
+        }
+
+        let pending = self.pending.remove(&key).unwrap();
+        let data = pending
+            .parts
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect::<Vec<u8>>();
+        let length = match protocol_parser::parse_message_length(&data) {
+            Ok((_, length)) if length as usize <= data.len() - MESSAGE_LENGTH_SIZE => {
+                length as usize
+            }
+            _ => return Reassembly::Invalid,
+        };
+
+        let mut message = PFPMessage::from(pending.first);
+        message.payload = data[MESSAGE_LENGTH_SIZE..MESSAGE_LENGTH_SIZE + length].to_vec();
+        Reassembly::Complete(message)
+    }
+
+    /// Drop the messages that did not complete in time, returns how many were dropped.
+    pub fn expire(&mut self, now: Instant) -> usize {
+        let before = self.pending.len();
+        let timeout = self.timeout;
+        self.pending
+            .retain(|_, pending| now.duration_since(pending.started_at) < timeout);
+        before - self.pending.len()
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some(key) = self
+            .pending
+            .iter()
+            .min_by_key(|(_, pending)| pending.started_at)
+            .map(|(key, _)| *key)
+        {
+            self.pending.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{wire_u16, Command};
+
+    fn parts(source_addr: u32, request_id: u8, data: &[u8]) -> Vec<PFPRequest> {
+        PFPRequest::fragment(Command::Push, source_addr, 0, request_id, data).unwrap()
+    }
+
+    fn complete(reassembly: Reassembly) -> PFPMessage {
+        match reassembly {
+            Reassembly::Complete(message) => message,
+            other => panic!("expected a complete message, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn single_part_is_complete() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let request = parts(1, 1, &[1, 2, 3]).remove(0);
+        let message = complete(reassembler.push(request.clone(), Instant::now()));
+        assert_eq!(message.payload, request.payload);
+    }
+
+    #[test]
+    fn out_of_order_parts() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let data = (0..100).collect::<Vec<u8>>();
+        let mut requests = parts(1, 1, &data);
+        requests.reverse();
+        let last = requests.pop().unwrap();
+
+        let now = Instant::now();
+        for request in requests {
+            assert!(matches!(
+                reassembler.push(request, now),
+                Reassembly::Pending
+            ));
+        }
+        let message = complete(reassembler.push(last, now));
+        assert_eq!(message.payload, data);
+        assert_eq!(message.source_addr, 1);
+        assert_eq!(message.request_id, 1);
+    }
+
+    #[test]
+    fn duplicate_parts() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let requests = parts(1, 1, &[7; 50]);
+        let now = Instant::now();
+
+        assert!(matches!(
+            reassembler.push(requests[0].clone(), now),
+            Reassembly::Pending
+        ));
+        assert!(matches!(
+            reassembler.push(requests[0].clone(), now),
+            Reassembly::Duplicate
+        ));
+        let message = complete(reassembler.push(requests[1].clone(), now));
+        assert_eq!(message.payload, [7; 50]);
+    }
+
+    #[test]
+    fn invalid_parts() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let mut request = parts(1, 1, &[1]).remove(0);
+        request.request_part = 1;
+        assert!(matches!(
+            reassembler.push(request.clone(), Instant::now()),
+            Reassembly::Invalid
+        ));
+        request.request_count = 3;
+        request.request_part = 3;
+        assert!(matches!(
+            reassembler.push(request, Instant::now()),
+            Reassembly::Invalid
+        ));
+    }
+
+    #[test]
+    fn request_count_mismatch_restarts_the_message() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let now = Instant::now();
+        let old = parts(1, 1, &[1; 50]);
+        let new = parts(1, 1, &[2; 80]);
+        assert_eq!((old.len(), new.len()), (2, 3));
+
+        assert!(matches!(
+            reassembler.push(old[0].clone(), now),
+            Reassembly::Pending
+        ));
+        assert!(matches!(
+            reassembler.push(new[1].clone(), now),
+            Reassembly::Pending
+        ));
+        assert!(matches!(
+            reassembler.push(new[2].clone(), now),
+            Reassembly::Pending
+        ));
+        // The part of the old message was dropped with it
+        let message = complete(reassembler.push(new[0].clone(), now));
+        assert_eq!(message.payload, [2; 80]);
+    }
+
+    #[test]
+    fn incomplete_messages_expire() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let now = Instant::now();
+        let requests = parts(1, 1, &[1; 50]);
+        reassembler.push(requests[0].clone(), now);
+
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT / 2), 0);
+        assert_eq!(reassembler.expire(now + REASSEMBLY_TIMEOUT), 1);
+        assert!(matches!(
+            reassembler.push(requests[1].clone(), now + REASSEMBLY_TIMEOUT),
+            Reassembly::Pending
+        ));
+    }
+
+    #[test]
+    fn oldest_message_is_evicted() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let now = Instant::now();
+        let oldest = parts(0, 1, &[1; 50]);
+        reassembler.push(oldest[0].clone(), now);
+        for source_addr in 1..MAX_PENDING as u32 {
+            let requests = parts(source_addr, 1, &[1; 50]);
+            reassembler.push(requests[0].clone(), now + Duration::from_millis(1));
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+
+        let newest = parts(MAX_PENDING as u32, 1, &[1; 50]);
+        reassembler.push(newest[0].clone(), now + Duration::from_millis(2));
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+        assert!(!reassembler.pending.contains_key(&(0, 1)));
+        assert!(reassembler.pending.contains_key(&(MAX_PENDING as u32, 1)));
+    }
+
+    #[test]
+    fn length_prefix_longer_than_data() {
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT);
+        let now = Instant::now();
+        let mut requests = parts(1, 1, &[1; 50]);
+        requests[0].payload[..MESSAGE_LENGTH_SIZE].copy_from_slice(&wire_u16(1000));
+
+        reassembler.push(requests[0].clone(), now);
+        assert!(matches!(
+            reassembler.push(requests[1].clone(), now),
+            Reassembly::Invalid
+        ));
+    }
+}
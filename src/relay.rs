@@ -1,87 +1,982 @@
-use std::{sync::Arc, time::Duration};
+use std::{fmt, path::PathBuf, sync::Arc, time::Duration};
 
+use eyre::eyre;
 use futures::future::BoxFuture;
-use tokio::sync::{oneshot::Receiver, RwLock};
-use tracing::{debug, warn};
+use tokio::sync::oneshot::Receiver;
+use tracing::{debug, info, warn};
 
 use crate::{
+    admission::{AccessList, Admission},
+    auth::DeviceKeys,
+    checksum::Checksum,
+    dedup::{DedupCache, DEDUP_WINDOW},
+    devices::{DeviceRegistry, DeviceState},
+    encryption::DeviceCiphers,
+    framing::FramingError,
+    handshake::{Handshake, HandshakeAction},
     logger::slice_to_hex,
-    protocol::{Command, PFPRequest},
+    outbound::{Outbound, MAX_ATTEMPTS, RETRY_BACKOFF},
+    payload::Payload,
+    protocol::{
+        is_device_addr, Command, PFPMessage, PFPRequest, ProtocolError, BROADCAST_ADDR, RELAY_ADDR,
+    },
     protocol_parser,
+    reassembly::{Reassembler, Reassembly, REASSEMBLY_TIMEOUT},
+    replay::{Freshness, ReplayGuard},
+    request_id::RequestIdAllocator,
+    routing::RoutingTable,
     serial::SimpleSerial,
+    topology::{Topology, TopologyFormat},
 };
 
+/// Counters about the traffic seen by the relay.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RelayStats {
+    received: u64,
+    unknown_commands: u64,
+    malformed: u64,
+    invalid_sources: u64,
+    corrupted: u64,
+    unauthenticated: u64,
+    looped: u64,
+    hop_limit: u64,
+    denied: u64,
+    forwarded: u64,
+    not_for_us: u64,
+    duplicates: u64,
+    duplicate_parts: u64,
+    replayed: u64,
+    invalid_parts: u64,
+    expired_messages: u64,
+    sink_errors: u64,
+    event_errors: u64,
+    retries: u64,
+    undelivered: u64,
+}
+
+/// Formatted as InfluxDB line protocol fields.
+impl fmt::Display for RelayStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received={},unknown_commands={},malformed={},invalid_sources={},corrupted={},\
+             unauthenticated={},looped={},hop_limit={},denied={},forwarded={},not_for_us={},duplicates={},duplicate_parts={},\
+             replayed={},invalid_parts={},expired_messages={},sink_errors={},\
+             event_errors={},retries={},undelivered={}",
+            self.received,
+            self.unknown_commands,
+            self.malformed,
+            self.invalid_sources,
+            self.corrupted,
+            self.unauthenticated,
+            self.looped,
+            self.hop_limit,
+            self.denied,
+            self.forwarded,
+            self.not_for_us,
+            self.duplicates,
+            self.duplicate_parts,
+            self.replayed,
+            self.invalid_parts,
+            self.expired_messages,
+            self.sink_errors,
+            self.event_errors,
+            self.retries,
+            self.undelivered,
+        )
+    }
+}
+
+/// Delay between two parts of the same message, to let the radio keep up.
+const FRAGMENT_PACING: Duration = Duration::from_millis(50);
+/// How often devices are checked against their TTL.
+const TTL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the stats and the topology are published.
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+/// How often pending requests, handshakes and messages are checked.
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How often link-level neighbors are probed with `HeloL`.
+const NEIGHBOR_INTERVAL: Duration = Duration::from_secs(30);
+/// Neighbors that missed this many probes are not direct neighbors anymore.
+const NEIGHBOR_MISSED_PROBES: u32 = 3;
+/// How long the attached micro:bit has to answer [`query_relay_id`].
+const RELAY_ID_QUERY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Tunables of the [`Relay`].
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Integrity check carried by every packet
+    pub checksum: Checksum,
+    /// Devices silent for longer than this are considered gone
+    pub device_ttl: Duration,
+    /// Requests that went through more hops are dropped
+    pub max_hops: u8,
+    /// Re-emit the requests addressed to other nodes
+    pub forwarding: bool,
+    /// Devices allowed to join the mesh
+    pub access_list: AccessList,
+    /// Access list file, reloaded when it changes
+    pub access_list_file: Option<PathBuf>,
+    /// Keys of the devices whose requests must be authenticated
+    pub device_keys: DeviceKeys,
+    /// Keys of the devices whose payloads are encrypted
+    pub device_ciphers: DeviceCiphers,
+}
+
+/// Notable changes in the relay state, for the outside world.
+#[derive(Debug, Clone)]
+pub enum RelayEvent {
+    DeviceOnline {
+        addr: u32,
+    },
+    DeviceOffline {
+        addr: u32,
+    },
+    /// A request was never acknowledged by its destination
+    DeliveryFailed {
+        dest_addr: u32,
+        command_id: u8,
+        request_id: u8,
+    },
+    /// Traffic counters, sent every minute
+    Stats(RelayStats),
+    /// Snapshot of the mesh topology, as JSON and Graphviz DOT
+    Topology {
+        json: String,
+        dot: String,
+    },
+}
+
 pub struct Relay {
     id: u32,
-    serial: Arc<RwLock<SimpleSerial>>,
-    on_request: Box<dyn Fn(Arc<PFPRequest>) -> BoxFuture<'static, crate::Result<()>>>,
+    config: RelayConfig,
+    serial: Arc<SimpleSerial>,
+    on_request: Box<dyn Fn(Arc<PFPMessage>) -> BoxFuture<'static, crate::Result<()>>>,
+    on_event: Box<dyn Fn(RelayEvent) -> BoxFuture<'static, crate::Result<()>>>,
     shutdown_signal: Receiver<()>,
-    devices: Vec<()>,
+    devices: DeviceRegistry,
+    topology: Topology,
+    routes: RoutingTable,
+    dedup: DedupCache,
+    /// Requests of denied devices already answered
+    denied: DedupCache,
+    admission: Admission,
+    handshake: Handshake,
+    outbound: Outbound,
+    reassembler: Reassembler,
+    replay: ReplayGuard,
+    request_ids: RequestIdAllocator,
+    stats: RelayStats,
 }
 
 impl Relay {
     pub fn new(
         id: u32,
-        serial: Arc<RwLock<SimpleSerial>>,
-        on_request: impl Fn(Arc<PFPRequest>) -> BoxFuture<'static, crate::Result<()>> + 'static,
+        config: RelayConfig,
+        serial: Arc<SimpleSerial>,
+        on_request: impl Fn(Arc<PFPMessage>) -> BoxFuture<'static, crate::Result<()>> + 'static,
+        on_event: impl Fn(RelayEvent) -> BoxFuture<'static, crate::Result<()>> + 'static,
         shutdown_signal: Receiver<()>,
     ) -> Self {
         Self {
             id,
+            admission: Admission::new(config.access_list.clone(), config.access_list_file.clone()),
+            config,
             serial,
             on_request: Box::new(on_request),
+            on_event: Box::new(on_event),
             shutdown_signal,
-            devices: Vec::new(),
+            devices: DeviceRegistry::default(),
+            topology: Topology::new(id),
+            routes: RoutingTable::default(),
+            dedup: DedupCache::new(DEDUP_WINDOW),
+            denied: DedupCache::new(DEDUP_WINDOW),
+            handshake: Handshake,
+            outbound: Outbound::new(RETRY_BACKOFF, MAX_ATTEMPTS),
+            reassembler: Reassembler::new(REASSEMBLY_TIMEOUT),
+            replay: ReplayGuard::default(),
+            request_ids: RequestIdAllocator::default(),
+            stats: RelayStats::default(),
         }
     }
 
     pub async fn run(&mut self) -> crate::Result<()> {
-        let mut ttl = tokio::time::Instant::now();
-        let mut timeout = tokio::time::interval(Duration::from_secs(1));
+        let now = tokio::time::Instant::now();
+        let mut ttl = housekeeping_timer(now + TTL_CHECK_INTERVAL, TTL_CHECK_INTERVAL);
+        let mut neighbors = housekeeping_timer(now, NEIGHBOR_INTERVAL);
+        let mut stats = housekeeping_timer(now + STATS_INTERVAL, STATS_INTERVAL);
+        let mut pending = housekeeping_timer(now, PENDING_CHECK_INTERVAL);
+        let serial = self.serial.clone();
 
+        // The pending timer wakes the loop up at least once per
+        // `PENDING_CHECK_INTERVAL`, so the shutdown signal is noticed quickly
         while self.shutdown_signal.try_recv().is_err() {
-            if ttl.elapsed().as_secs() >= 60 {
-                ttl = tokio::time::Instant::now();
-                // TODO: Check TTLs
-            }
+            tokio::select! {
+                line = serial.read_frame() => match line {
+                    Ok(line) => self.handle_line(line).await?,
+                    Err(err) if err.downcast_ref::<FramingError>().is_some() => {
+                        self.stats.malformed += 1;
+                        warn!(%err, "Invalid frame");
+                    }
+                    // The port is closed or failing, nothing more will come
+                    Err(err) => return Err(err),
+                },
+                _ = ttl.tick() => {
+                    self.admission.reload();
+                    self.expire_devices().await?;
+                }
+                _ = neighbors.tick() => {
+                    debug!("Probing neighbors");
+                    self.broadcast(&Payload::HeloL).await?;
+                }
+                _ = stats.tick() => self.report_stats().await,
+                _ = pending.tick() => {
+                    let now = tokio::time::Instant::now();
+                    self.dedup.expire(now);
+                    self.denied.expire(now);
+                    self.stats.expired_messages += self.reassembler.expire(now) as u64;
 
-            let timeout = timeout.tick();
-            tokio::pin!(timeout);
-            let line = async { self.serial.write().await.read_line() };
-            tokio::pin!(line);
+                    self.poll_handshakes().await?;
+                    self.poll_outbound().await?;
 
-            tokio::select! {
-                _ = &mut timeout => (),
-                line = &mut line => {
-                    if let Ok(line) = line {
-                        if let Ok((_, request)) = protocol_parser::parse(&line) {
-                            let request = Arc::new(request);
-                            (self.on_request)(request.clone()).await?;
-
-                            match Command::from(request.command_id) {
-                                Command::HeloP => (),
-                                Command::Push => (),
-                                _ => (),
-                            }
-                        } else {
-                            warn!(
-                                line = String::from_utf8(line).unwrap_or_else(|_| String::new()),
-                                "Invalid request"
-                            );
-                        }
+                    if self.devices.is_empty() {
+                        debug!("Discovering devices");
+                        self.send(Command::HeloP, BROADCAST_ADDR, &[]).await?;
                     }
                 }
             }
+        }
+
+        Ok(())
+    }
+
+    /// Send `data` to `dest_addr`, split over as many parts as needed.
+    pub async fn send(
+        &mut self,
+        command: Command,
+        dest_addr: u32,
+        data: &[u8],
+    ) -> crate::Result<()> {
+        let request_id = self.request_ids.next(dest_addr);
+
+        let requests = PFPRequest::fragment(command, self.id, dest_addr, request_id, data)?;
+        for (request_part, request) in requests.into_iter().enumerate() {
+            if request_part > 0 {
+                tokio::time::sleep(FRAGMENT_PACING).await;
+            }
+            self.send_request(request).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Send `payload` to the device at `dest_addr`.
+    pub async fn unicast(&mut self, dest_addr: u32, payload: &Payload) -> crate::Result<()> {
+        if !is_device_addr(dest_addr) {
+            return Err(eyre!("{dest_addr:#010x} is not a device address"));
+        }
+        self.send_payload(dest_addr, payload).await
+    }
+
+    /// Send `payload` to every node.
+    pub async fn broadcast(&mut self, payload: &Payload) -> crate::Result<()> {
+        self.send_payload(BROADCAST_ADDR, payload).await
+    }
+
+    /// Send a single part request carrying `payload` to `dest_addr`.
+    async fn send_payload(&mut self, dest_addr: u32, payload: &Payload) -> crate::Result<()> {
+        let request = PFPRequest::with_payload(self.id, payload)
+            .dest_addr(dest_addr)
+            .request_id(self.request_ids.next(dest_addr))
+            .build();
+        self.send_request(request).await
+    }
+
+    /// Send `request` and track it until acknowledged if it expects a response.
+    async fn send_request(&mut self, request: PFPRequest) -> crate::Result<()> {
+        self.write_request(&request).await?;
+        self.outbound.track(&request, tokio::time::Instant::now());
+        Ok(())
+    }
+
+    async fn write_request(&mut self, request: &PFPRequest) -> crate::Result<()> {
+        let mut request = request.clone();
+        let mut trailer = self
+            .config
+            .device_ciphers
+            .seal(request.dest_addr, &mut request)
+            .unwrap_or_default();
+        trailer.extend(
+            self.config
+                .device_keys
+                .sign(request.dest_addr, &request)
+                .iter()
+                .flatten(),
+        );
+        self.write_packet(&request, &trailer).await
+    }
+
+    /// Write `request` followed by its encryption and authentication `trailer`.
+    async fn write_packet(&mut self, request: &PFPRequest, trailer: &[u8]) -> crate::Result<()> {
+        let mut packet = Vec::from(request.clone());
+        packet.extend_from_slice(trailer);
+        let packet = self.config.checksum.seal(packet);
+        debug!(
+            packet = slice_to_hex(&packet),
+            request_id = request.request_id,
+            request_part = request.request_part,
+            request_count = request.request_count,
+            "Sending request"
+        );
+        self.serial.write_buf(&packet).await
+    }
+
+    /// Retry the unacknowledged requests and report the lost ones.
+    async fn poll_outbound(&mut self) -> crate::Result<()> {
+        let now = tokio::time::Instant::now();
+        let poll = self.outbound.poll(now);
+
+        for request in poll.retries {
+            self.stats.retries += 1;
+            debug!(
+                dest_addr = request.dest_addr,
+                command_id = request.command_id,
+                request_id = request.request_id,
+                "Retrying request"
+            );
+            self.write_request(&request).await?;
+        }
+
+        for request in poll.failures {
+            self.stats.undelivered += 1;
+            warn!(
+                dest_addr = request.dest_addr,
+                command_id = request.command_id,
+                request_id = request.request_id,
+                "Request never acknowledged"
+            );
+            if let Some(device) = self.devices.get_mut(request.dest_addr) {
+                let before = device.state;
+                self.handshake.on_timeout(device, now);
+                let after = device.state;
+                self.apply_handshake(request.dest_addr, before, after, Vec::new())
+                    .await?;
+            }
+            self.emit(RelayEvent::DeliveryFailed {
+                dest_addr: request.dest_addr,
+                command_id: request.command_id,
+                request_id: request.request_id,
+            })
+            .await;
+        }
+
+        Ok(())
+    }
+
+    async fn poll_handshakes(&mut self) -> crate::Result<()> {
+        let now = tokio::time::Instant::now();
+        let mut steps = Vec::new();
+        for device in self.devices.iter_mut() {
+            let before = device.state;
+            let actions = self.handshake.poll(device, now);
+            steps.push((device.addr, before, device.state, actions));
+        }
+
+        for (addr, before, after, actions) in steps {
+            self.apply_handshake(addr, before, after, actions).await?;
+        }
 
-            if self.devices.is_empty() {
-                let packet = Vec::from(PFPRequest::new_helop(self.id));
-                debug!(packet = slice_to_hex(&packet), "Discovering devices");
-                self.serial.write().await.write_buf(&packet)?;
+        Ok(())
+    }
+
+    async fn apply_handshake(
+        &mut self,
+        addr: u32,
+        before: DeviceState,
+        after: DeviceState,
+        actions: Vec<HandshakeAction>,
+    ) -> crate::Result<()> {
+        if before != after {
+            match after {
+                DeviceState::Trusted => info!(addr, "Device trusted"),
+                DeviceState::Failed => warn!(addr, state = ?before, "Handshake failed"),
+                _ => debug!(addr, from = ?before, to = ?after, "Handshake step"),
             }
+        }
 
-            tokio::time::sleep(Duration::from_secs(1)).await;
+        for action in actions {
+            if let HandshakeAction::Reply(Payload::Ident | Payload::TrustB) = action {
+                // A restarted handshake must not fail on the requests of the previous one
+                self.outbound
+                    .cancel(addr, &[Command::Ident, Command::TrustB]);
+            }
+            match action {
+                HandshakeAction::Reply(payload) => self.reply(addr, &payload).await?,
+                HandshakeAction::Broadcast(payload) => self.broadcast(&payload).await?,
+            }
         }
 
         Ok(())
     }
+
+    /// Whether `request` is addressed to another node the relay knows a way to.
+    ///
+    /// Requests are not sent back towards the forwarder they came from.
+    fn should_forward(&self, request: &PFPRequest) -> bool {
+        if self.is_for_us(request) {
+            return false;
+        }
+
+        let came_from = match request.forwarded_by_addr {
+            0 => request.source_addr,
+            forwarded_by_addr => forwarded_by_addr,
+        };
+        self.routes
+            .get(request.dest_addr)
+            .is_some_and(|route| route.next_hop != came_from)
+    }
+
+    /// Whether `request` is addressed to the relay, directly or not.
+    fn is_for_us(&self, request: &PFPRequest) -> bool {
+        request.is_broadcast() || request.dest_addr == RELAY_ADDR || request.dest_addr == self.id
+    }
+
+    /// Re-emit `request` on behalf of its source, keeping its original `trailer`.
+    async fn forward(&mut self, mut request: PFPRequest, trailer: &[u8]) -> crate::Result<()> {
+        if request.hop_count >= self.config.max_hops {
+            self.stats.hop_limit += 1;
+            return Ok(());
+        }
+
+        request.hop_count += 1;
+        request.forwarded_by_addr = self.id;
+        self.stats.forwarded += 1;
+        debug!(
+            source_addr = request.source_addr,
+            dest_addr = request.dest_addr,
+            hop_count = request.hop_count,
+            "Forwarding request"
+        );
+        self.write_packet(&request, trailer).await
+    }
+
+    /// Answer the device at `dest_addr`, a peer claiming a reserved address
+    /// is not worth stopping the relay for.
+    async fn reply(&mut self, dest_addr: u32, payload: &Payload) -> crate::Result<()> {
+        if !is_device_addr(dest_addr) {
+            self.stats.invalid_sources += 1;
+            warn!(dest_addr, ?payload, "Not replying to a reserved address");
+            return Ok(());
+        }
+        self.unicast(dest_addr, payload).await
+    }
+
+    /// Acknowledge the `Push` of `dest_addr`, echoing its `request_id`.
+    async fn ack_push(&mut self, dest_addr: u32, request_id: u8) -> crate::Result<()> {
+        let ack = PFPRequest::with_payload(self.id, &Payload::PushAck)
+            .dest_addr(dest_addr)
+            .request_id(request_id)
+            .build();
+        self.send_request(ack).await
+    }
+
+    /// Log the counters and publish them along with the topology.
+    async fn report_stats(&mut self) {
+        let now = tokio::time::Instant::now();
+        let neighbors = self
+            .devices
+            .neighbors(now, NEIGHBOR_INTERVAL * NEIGHBOR_MISSED_PROBES);
+        info!(
+            devices = self.devices.len(),
+            neighbors = ?neighbors,
+            stats = %self.stats,
+            "Relay stats"
+        );
+        self.emit(RelayEvent::Stats(self.stats)).await;
+
+        self.emit(RelayEvent::Topology {
+            json: self.topology.export(TopologyFormat::Json, now),
+            dot: self.topology.export(TopologyFormat::Dot, now),
+        })
+        .await;
+    }
+
+    /// Hand `event` to the outside world, a failure does not stop the relay.
+    async fn emit(&mut self, event: RelayEvent) {
+        if let Err(err) = (self.on_event)(event).await {
+            self.stats.event_errors += 1;
+            warn!(%err, "Failed to publish event");
+        }
+    }
+
+    /// Forget the devices silent for longer than their TTL and tell the network.
+    async fn expire_devices(&mut self) -> crate::Result<()> {
+        let now = tokio::time::Instant::now();
+        self.topology.expire(now, self.config.device_ttl);
+        self.routes.expire(now, self.config.device_ttl);
+        let expired = self.devices.expire(now, self.config.device_ttl);
+
+        for device in expired {
+            info!(
+                addr = device.addr,
+                last_seen = ?device.last_seen.elapsed(),
+                "Device expired"
+            );
+            let request_id = self.request_ids.next(BROADCAST_ADDR);
+            self.send_request(PFPRequest::new_del(self.id, request_id, device.addr))
+                .await?;
+            self.emit(RelayEvent::DeviceOffline { addr: device.addr })
+                .await;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_line(&mut self, line: Vec<u8>) -> crate::Result<()> {
+        self.stats.received += 1;
+
+        let (mut request, trailer) = match self
+            .config
+            .checksum
+            .open(&line)
+            .and_then(|packet| self.config.device_keys.open(packet))
+        {
+            Ok(decoded) => decoded,
+            Err(ProtocolError::UnknownCommand(command_id)) => {
+                self.stats.unknown_commands += 1;
+                warn!(command_id, packet = slice_to_hex(&line), "Unknown command");
+                return Ok(());
+            }
+            Err(ProtocolError::ChecksumMismatch) => {
+                self.stats.corrupted += 1;
+                warn!(packet = slice_to_hex(&line), "Corrupted request");
+                return Ok(());
+            }
+            Err(ProtocolError::Unauthenticated) => {
+                self.stats.unauthenticated += 1;
+                warn!(packet = slice_to_hex(&line), "Unauthenticated request");
+                return Ok(());
+            }
+            Err(err) => {
+                self.stats.malformed += 1;
+                warn!(
+                    line = String::from_utf8(line).unwrap_or_else(|_| String::new()),
+                    %err,
+                    "Invalid request"
+                );
+                return Ok(());
+            }
+        };
+
+        if !is_device_addr(request.source_addr) {
+            self.stats.invalid_sources += 1;
+            warn!(
+                source_addr = request.source_addr,
+                packet = slice_to_hex(&line),
+                "Request from a reserved address"
+            );
+            return Ok(());
+        }
+        if request.source_addr == self.id || request.forwarded_by_addr == self.id {
+            self.stats.looped += 1;
+            debug!(
+                source_addr = request.source_addr,
+                forwarded_by_addr = request.forwarded_by_addr,
+                request_id = request.request_id,
+                "Dropped looping request"
+            );
+            return Ok(());
+        }
+        if request.hop_count > self.config.max_hops {
+            self.stats.hop_limit += 1;
+            debug!(
+                source_addr = request.source_addr,
+                hop_count = request.hop_count,
+                "Dropped request over the hop limit"
+            );
+            return Ok(());
+        }
+
+        if !self.admission.is_allowed(request.source_addr) {
+            self.stats.denied += 1;
+            let reply = match Command::try_from(request.command_id) {
+                Ok(Command::HeloP | Command::OlehP | Command::Tnedi | Command::TrustT) => {
+                    Some(Payload::ADeny)
+                }
+                Ok(Command::Push) => Some(Payload::UDeny),
+                _ => None,
+            };
+            debug!(
+                source_addr = request.source_addr,
+                command_id = request.command_id,
+                "Dropped request from denied device"
+            );
+            // Answered once, and only when meant for the relay, so that a
+            // denied device flooding the mesh is not echoed back
+            let now = tokio::time::Instant::now();
+            if let Some(reply) = reply {
+                if request.request_part == 0
+                    && self.is_for_us(&request)
+                    && self.denied.insert(&request, now)
+                {
+                    self.reply(request.source_addr, &reply).await?;
+                }
+            }
+            return Ok(());
+        }
+
+        let now = tokio::time::Instant::now();
+        self.topology.observe(&request, now);
+        self.routes.learn(&request, now, self.config.device_ttl);
+
+        if let Some(device) = self.devices.observe(&request, now) {
+            if device.first_seen == now {
+                info!(
+                    addr = device.addr,
+                    hop_count = device.hop_count,
+                    forwarded_by_addr = device.forwarded_by_addr,
+                    "New device"
+                );
+                let addr = device.addr;
+                self.emit(RelayEvent::DeviceOnline { addr }).await;
+            }
+        }
+
+        let source_addr = request.source_addr;
+        let request_id = request.request_id;
+        let request_count = request.request_count;
+        // A `Push` is only remembered once delivered, so that a copy sent
+        // again after a sink error still reaches the sink
+        let is_push = request.command_id == Command::Push;
+        let duplicate = if is_push {
+            self.dedup.contains(&request, now)
+        } else {
+            !self.dedup.insert(&request, now)
+        };
+        if duplicate {
+            self.stats.duplicates += 1;
+            debug!(
+                source_addr,
+                request_id,
+                forwarded_by_addr = request.forwarded_by_addr,
+                "Duplicate request"
+            );
+            if is_push
+                && self.is_for_us(&request)
+                && request.request_part + 1 >= request.request_count
+            {
+                // The device sends it again when it missed the acknowledgement
+                self.ack_push(source_addr, request_id).await?;
+            }
+            return Ok(());
+        }
+
+        if self.config.forwarding && self.should_forward(&request) {
+            return self.forward(request, trailer).await;
+        }
+        if !self.is_for_us(&request) {
+            self.stats.not_for_us += 1;
+            debug!(
+                source_addr,
+                dest_addr = request.dest_addr,
+                request_id,
+                "Ignored request addressed to another node"
+            );
+            return Ok(());
+        }
+        if let Err(err) = self.config.device_ciphers.open(&mut request, trailer) {
+            self.stats.unauthenticated += 1;
+            warn!(source_addr, request_id, %err, "Cannot decrypt request");
+            return Ok(());
+        }
+
+        let message = match self.reassembler.push(request, now) {
+            Reassembly::Complete(message) => Arc::new(message),
+            Reassembly::Pending => return Ok(()),
+            Reassembly::Duplicate => {
+                self.stats.duplicate_parts += 1;
+                debug!(source_addr, request_id, "Duplicate request part");
+                return Ok(());
+            }
+            Reassembly::Invalid => {
+                self.stats.invalid_parts += 1;
+                warn!(source_addr, request_id, "Invalid request part");
+                return Ok(());
+            }
+        };
+        debug!(
+            command_id = message.command_id,
+            hop_count = message.hop_count,
+            source_addr = message.source_addr,
+            dest_addr = message.dest_addr,
+            forwarded_by_addr = message.forwarded_by_addr,
+            request_id = message.request_id,
+            payload = slice_to_hex(&message.payload),
+            "Received message"
+        );
+        let payload = match message.decode_payload() {
+            Ok(payload) => payload,
+            Err(err) => {
+                self.stats.malformed += 1;
+                warn!(%err, payload = slice_to_hex(&message.payload), "Invalid payload");
+                return Ok(());
+            }
+        };
+        let command = payload.command();
+        if self
+            .outbound
+            .acknowledge(message.source_addr, message.request_id, command)
+        {
+            debug!(
+                source_addr = message.source_addr,
+                request_id = message.request_id,
+                "Request acknowledged"
+            );
+        }
+
+        // Only authenticated sequences can be trusted, see `crate::replay`
+        let sequence = match payload {
+            Payload::Push { sequence, .. } if self.config.device_keys.contains(source_addr) => {
+                Some(sequence)
+            }
+            _ => None,
+        };
+        if let Some(sequence) = sequence {
+            match self.replay.check(source_addr, sequence) {
+                Freshness::Fresh => (),
+                Freshness::Accepted => {
+                    self.stats.duplicates += 1;
+                    debug!(source_addr, request_id, sequence, "Push already delivered");
+                    return self.ack_push(source_addr, request_id).await;
+                }
+                Freshness::Stale => {
+                    self.stats.replayed += 1;
+                    warn!(
+                        source_addr,
+                        request_id, sequence, "Dropped replayed request"
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        match (self.on_request)(message.clone()).await {
+            Ok(()) if command == Command::Push => {
+                if let Some(sequence) = sequence {
+                    self.replay.accept(source_addr, sequence);
+                }
+                self.dedup.insert_message(&message, request_count, now);
+                self.ack_push(source_addr, request_id).await?;
+            }
+            Ok(()) => (),
+            // Not acknowledged, the device will send it again
+            Err(err) => {
+                self.stats.sink_errors += 1;
+                warn!(
+                    source_addr = message.source_addr,
+                    request_id = message.request_id,
+                    %err,
+                    "Failed to handle message"
+                );
+            }
+        }
+
+        if payload == Payload::HeloL {
+            self.reply(message.source_addr, &Payload::OlehL).await?;
+        }
+
+        if let Some(device) = self.devices.get_mut(message.source_addr) {
+            let before = device.state;
+            let actions = self.handshake.on_payload(device, &payload);
+            let after = device.state;
+            self.apply_handshake(message.source_addr, before, after, actions)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Timer ticking every `period` from `start`, without bursts to catch up
+/// after a slow iteration.
+fn housekeeping_timer(start: tokio::time::Instant, period: Duration) -> tokio::time::Interval {
+    let mut timer = tokio::time::interval_at(start, period);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    timer
+}
+
+/// Check that `id` can be used as the address of a relay.
+pub fn validate_relay_id(id: u32) -> crate::Result<u32> {
+    if id == BROADCAST_ADDR || id == RELAY_ADDR {
+        return Err(eyre!("{id:#010x} is a reserved address"));
+    }
+    Ok(id)
+}
+
+/// Ask the micro:bit attached to `serial` for its serial number, to be used
+/// as the relay id.
+///
+/// The `Ident` request goes from [`RELAY_ADDR`] to [`RELAY_ADDR`], which the
+/// attached micro:bit answers itself instead of sending it over the radio.
+/// Only a `Tnedi` echoing the `request_id` of the query, that did not travel
+/// over the radio, is accepted, so a neighbor looking for its relay at the
+/// same time is not mistaken for the attached micro:bit.
+pub async fn query_relay_id(serial: &SimpleSerial, checksum: Checksum) -> crate::Result<u32> {
+    let request_id = query_request_id();
+    let request = PFPRequest::with_payload(RELAY_ADDR, &Payload::Ident)
+        .dest_addr(RELAY_ADDR)
+        .request_id(request_id)
+        .build();
+    serial.write_buf(&checksum.seal(Vec::from(request))).await?;
+
+    tokio::time::timeout(
+        RELAY_ID_QUERY_TIMEOUT,
+        read_relay_id(serial, checksum, request_id),
+    )
+    .await
+    .map_err(|_| eyre!("The attached micro:bit did not send its serial number"))?
+}
+
+/// Hard to guess `request_id` for [`query_relay_id`], never the 0 of the
+/// requests built without one.
+fn query_request_id() -> u8 {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    (nanos % u8::MAX as u32) as u8 + 1
+}
+
+/// Wait for the `Tnedi` answering [`query_relay_id`].
+async fn read_relay_id(
+    serial: &SimpleSerial,
+    checksum: Checksum,
+    request_id: u8,
+) -> crate::Result<u32> {
+    loop {
+        let frame = match serial.read_frame().await {
+            Ok(frame) => frame,
+            Err(err) if err.downcast_ref::<FramingError>().is_some() => continue,
+            Err(err) => return Err(err),
+        };
+        let Ok(response) = checksum.open(&frame).and_then(protocol_parser::decode) else {
+            continue;
+        };
+        if response.command_id != Command::Tnedi
+            || response.dest_addr != RELAY_ADDR
+            || response.request_id != request_id
+            || response.hop_count != 0
+            || response.forwarded_by_addr != 0
+        {
+            continue;
+        }
+        if let Ok(Payload::Tnedi { serial }) = Payload::decode(Command::Tnedi, &response.payload) {
+            return validate_relay_id(serial);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::oneshot;
+    use tokio_serial::SerialStream;
+
+    use super::*;
+    use crate::{framing::Framing, replay::REPLAY_WINDOW};
+
+    const RELAY: u32 = 0x0000_0001;
+    const KEYED: u32 = 0x1234_5678;
+    const LEGACY: u32 = 0x0BAD_CAFE;
+
+    /// `(source_addr, request_id)` of the pushes published by the relay.
+    type Published = Arc<Mutex<Vec<(u32, u8)>>>;
+
+    /// Relay attached to a pseudo terminal, and the other end of it.
+    fn relay() -> (Relay, SerialStream, Published) {
+        let (serial, peer) = SerialStream::pair().unwrap();
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let config = RelayConfig {
+            checksum: Checksum::None,
+            device_ttl: Duration::from_secs(180),
+            max_hops: 8,
+            forwarding: false,
+            access_list: AccessList::default(),
+            access_list_file: None,
+            device_keys: DeviceKeys::parse("0x12345678 00112233445566778899aabbccddeeff").unwrap(),
+            device_ciphers: DeviceCiphers::default(),
+        };
+        let on_request = {
+            let published = published.clone();
+            move |message: Arc<PFPMessage>| {
+                if message.command_id == Command::Push {
+                    let push = (message.source_addr, message.request_id);
+                    published.lock().unwrap().push(push);
+                }
+                Box::pin(async { Ok(()) }) as BoxFuture<'static, crate::Result<()>>
+            }
+        };
+        let relay = Relay::new(
+            RELAY,
+            config,
+            Arc::new(SimpleSerial::from_stream(serial, Framing::Cobs)),
+            on_request,
+            |_| Box::pin(async { Ok(()) }),
+            oneshot::channel().1,
+        );
+        (relay, peer, published)
+    }
+
+    /// `Push` packet from `source_addr`, signed when it has a key.
+    fn push(relay: &Relay, source_addr: u32, request_id: u8, sequence: u32) -> Vec<u8> {
+        let payload = Payload::Push {
+            serial: source_addr,
+            intensity: 42,
+            sequence,
+        };
+        let request = PFPRequest::with_payload(source_addr, &payload)
+            .dest_addr(RELAY)
+            .request_id(request_id)
+            .build();
+        let mac = relay.config.device_keys.sign(source_addr, &request);
+        let mut packet = Vec::from(request);
+        packet.extend(mac.iter().flatten());
+        packet
+    }
+
+    #[tokio::test]
+    async fn replayed_pushes_of_keyed_devices_are_dropped() {
+        let (mut relay, _peer, published) = relay();
+        let captured = push(&relay, KEYED, 1, 5);
+        relay.handle_line(captured.clone()).await.unwrap();
+        let newer = push(&relay, KEYED, 2, 5 + REPLAY_WINDOW);
+        relay.handle_line(newer).await.unwrap();
+
+        // Played again once the relay forgot the copies it saw
+        relay
+            .dedup
+            .expire(tokio::time::Instant::now() + DEDUP_WINDOW * 2);
+        relay.handle_line(captured).await.unwrap();
+
+        assert_eq!(*published.lock().unwrap(), [(KEYED, 1), (KEYED, 2)]);
+        assert_eq!(relay.stats.replayed, 1);
+    }
+
+    #[tokio::test]
+    async fn legacy_pushes_of_unkeyed_devices_get_through() {
+        let (mut relay, _peer, published) = relay();
+        // Devices older than wire version 2 leave `sequence` at 0
+        for request_id in 1..=3 {
+            let packet = push(&relay, LEGACY, request_id, 0);
+            relay.handle_line(packet).await.unwrap();
+        }
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            [(LEGACY, 1), (LEGACY, 2), (LEGACY, 3)]
+        );
+        assert_eq!(relay.stats.replayed, 0);
+        assert_eq!(relay.stats.duplicates, 0);
+    }
 }
@@ -0,0 +1,17 @@
+use std::collections::HashMap;
+
+/// Hands out request ids, with one wrapping counter per destination.
+#[derive(Debug, Default)]
+pub struct RequestIdAllocator {
+    next: HashMap<u32, u8>,
+}
+
+impl RequestIdAllocator {
+    /// Next request id for `dest_addr`, the first one being 1.
+    pub fn next(&mut self, dest_addr: u32) -> u8 {
+        let next = self.next.entry(dest_addr).or_insert(1);
+        let request_id = *next;
+        *next = next.wrapping_add(1);
+        request_id
+    }
+}
@@ -0,0 +1,60 @@
+use std::{collections::HashMap, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::protocol::PFPRequest;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    /// Node delivering the traffic of the destination to the relay
+    pub next_hop: u32,
+    /// Hops between the relay and the destination
+    pub hops: u8,
+    pub last_seen: Instant,
+}
+
+/// Routes to the other nodes, learned from the requests they send.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: HashMap<u32, Route>,
+}
+
+impl RoutingTable {
+    /// Learn the route back to the source of `request`.
+    ///
+    /// A shorter route always wins, a longer one only replaces a route that
+    /// was not refreshed for `max_age`.
+    pub fn learn(&mut self, request: &PFPRequest, now: Instant, max_age: Duration) {
+        let route = if request.forwarded_by_addr == 0 {
+            Route {
+                next_hop: request.source_addr,
+                hops: 1,
+                last_seen: now,
+            }
+        } else {
+            Route {
+                next_hop: request.forwarded_by_addr,
+                hops: request.hop_count.saturating_add(1),
+                last_seen: now,
+            }
+        };
+
+        match self.routes.get(&request.source_addr) {
+            Some(current)
+                if current.hops < route.hops
+                    && now.duration_since(current.last_seen) <= max_age => {}
+            _ => {
+                self.routes.insert(request.source_addr, route);
+            }
+        }
+    }
+
+    pub fn get(&self, dest_addr: u32) -> Option<&Route> {
+        self.routes.get(&dest_addr)
+    }
+
+    pub fn expire(&mut self, now: Instant, max_age: Duration) {
+        self.routes
+            .retain(|_, route| now.duration_since(route.last_seen) <= max_age);
+    }
+}
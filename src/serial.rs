@@ -1,40 +1,58 @@
-use std::{
-    io::{BufReader, BufWriter, Write},
-    time::Duration,
+use eyre::eyre;
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
 };
-
+use tokio::sync::Mutex;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::Framed;
 use tracing::debug;
 
-use crate::read_until::ReadUntilPat;
-
-const SERIAL_TIMEOUT: Duration = Duration::from_secs(5);
+use crate::framing::{FrameCodec, Framing};
 
+/// Serial port split into a framed reader and writer, so a pending read
+/// never holds back a write.
+///
+/// The reader keeps the bytes received past the end of a frame for the
+/// next one, and reads can be cancelled without losing data.
 pub struct SimpleSerial {
-    serial: Box<dyn tokio_serial::SerialPort>,
+    reader: Mutex<SplitStream<Framed<SerialStream, FrameCodec>>>,
+    writer: Mutex<SplitSink<Framed<SerialStream, FrameCodec>, Vec<u8>>>,
 }
 
 impl SimpleSerial {
-    pub fn new(path: &str, baud_rate: u32) -> crate::Result<Self> {
-        let serial = tokio_serial::new(path, baud_rate)
-            .baud_rate(baud_rate)
-            .timeout(SERIAL_TIMEOUT)
-            .open()?;
-        Ok(Self { serial })
+    pub fn new(path: &str, baud_rate: u32, framing: Framing) -> crate::Result<Self> {
+        let serial = tokio_serial::new(path, baud_rate).open_native_async()?;
+        Ok(Self::from_stream(serial, framing))
+    }
+
+    pub fn from_stream(serial: SerialStream, framing: Framing) -> Self {
+        let (writer, reader) = Framed::new(serial, FrameCodec::new(framing)).split();
+        Self {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+
+    pub async fn read_frame(&self) -> crate::Result<Vec<u8>> {
+        match self.reader.lock().await.next().await {
+            Some(frame) => Ok(frame??),
+            None => Err(eyre!("Serial port closed")),
+        }
     }
 
-    pub fn read_line(&mut self) -> crate::Result<Vec<u8>> {
-        let mut line = Vec::new();
-        let mut reader = BufReader::new(&mut self.serial);
-        reader.read_until_pat(b"\r\n", &mut line)?;
-        Ok(line)
+    /// Give back the serial port, dropping the bytes not read yet.
+    pub fn into_inner(self) -> SerialStream {
+        self.reader
+            .into_inner()
+            .reunite(self.writer.into_inner())
+            .expect("reader and writer come from the same stream")
+            .into_inner()
     }
 
-    pub fn write_buf(&mut self, buf: &[u8]) -> crate::Result<()> {
-        let buf = [buf, b"\r\n"].concat();
-        let mut writer = BufWriter::new(&mut self.serial);
-        debug!(packet = crate::logger::slice_to_hex(&buf), "buf");
-        writer.write_all(&buf)?;
-        writer.flush()?;
+    pub async fn write_buf(&self, buf: &[u8]) -> crate::Result<()> {
+        debug!(packet = crate::logger::slice_to_hex(buf), "buf");
+        self.writer.lock().await.send(buf.to_vec()).await?;
         Ok(())
     }
 }
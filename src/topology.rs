@@ -0,0 +1,155 @@
+use std::{collections::HashMap, fmt::Write, time::Duration};
+
+use clap::ValueEnum;
+use tokio::time::Instant;
+
+use crate::protocol::PFPRequest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TopologyFormat {
+    Json,
+    Dot,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    from: u32,
+    to: u32,
+    /// Hops between both ends, 1 being a direct radio link
+    hops: u8,
+    last_seen: Instant,
+}
+
+/// Radio mesh as seen from the relay, rebuilt from the header of incoming requests.
+///
+/// A request with no forwarder is a direct link between its source and the
+/// relay. A forwarded request is a direct link between the forwarder and
+/// the relay, and a path of `hop_count` hops between the source and the
+/// forwarder.
+pub struct Topology {
+    root: u32,
+    edges: HashMap<(u32, u32), Edge>,
+}
+
+impl Topology {
+    pub fn new(root: u32) -> Self {
+        Self {
+            root,
+            edges: HashMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, request: &PFPRequest, now: Instant) {
+        if request.source_addr == self.root {
+            return;
+        }
+
+        if request.forwarded_by_addr == 0 || request.forwarded_by_addr == request.source_addr {
+            self.link(request.source_addr, self.root, 1, now);
+        } else {
+            self.link(request.forwarded_by_addr, self.root, 1, now);
+            self.link(
+                request.source_addr,
+                request.forwarded_by_addr,
+                request.hop_count.max(1),
+                now,
+            );
+        }
+    }
+
+    /// Drop the edges not seen for `max_age`.
+    pub fn expire(&mut self, now: Instant, max_age: Duration) {
+        self.edges
+            .retain(|_, edge| now.duration_since(edge.last_seen) <= max_age);
+    }
+
+    pub fn export(&self, format: TopologyFormat, now: Instant) -> String {
+        match format {
+            TopologyFormat::Json => self.to_json(now),
+            TopologyFormat::Dot => self.to_dot(now),
+        }
+    }
+
+    fn link(&mut self, from: u32, to: u32, hops: u8, now: Instant) {
+        self.edges.insert(
+            (from, to),
+            Edge {
+                from,
+                to,
+                hops,
+                last_seen: now,
+            },
+        );
+    }
+
+    /// Nodes and edges, sorted so that exports are stable.
+    fn sorted(&self) -> (Vec<u32>, Vec<Edge>) {
+        let mut nodes = vec![self.root];
+        nodes.extend(self.edges.keys().flat_map(|&(from, to)| [from, to]));
+        nodes.sort_unstable();
+        nodes.dedup();
+
+        let mut edges = self.edges.values().copied().collect::<Vec<_>>();
+        edges.sort_unstable_by_key(|edge| (edge.from, edge.to));
+
+        (nodes, edges)
+    }
+
+    fn to_json(&self, now: Instant) -> String {
+        let (nodes, edges) = self.sorted();
+        let nodes = nodes
+            .iter()
+            .map(|addr| format!("{{\"addr\":{addr},\"relay\":{}}}", *addr == self.root))
+            .collect::<Vec<_>>()
+            .join(",");
+        let edges = edges
+            .iter()
+            .map(|edge| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"hops\":{},\"age\":{}}}",
+                    edge.from,
+                    edge.to,
+                    edge.hops,
+                    now.duration_since(edge.last_seen).as_secs()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            "{{\"root\":{},\"nodes\":[{nodes}],\"edges\":[{edges}]}}",
+            self.root
+        )
+    }
+
+    fn to_dot(&self, now: Instant) -> String {
+        let (nodes, edges) = self.sorted();
+        let mut dot = String::from("digraph pfp {\n");
+        for addr in nodes {
+            let shape = if addr == self.root { "box" } else { "ellipse" };
+            let _ = writeln!(dot, "    \"{addr:#010x}\" [shape={shape}];");
+        }
+        for Edge {
+            from,
+            to,
+            hops,
+            last_seen,
+        } in edges
+        {
+            let age = now.duration_since(last_seen).as_secs();
+            let _ = if hops > 1 {
+                writeln!(
+                    dot,
+                    "    \"{from:#010x}\" -> \"{to:#010x}\" [style=dashed, label=\"{hops} hops, {age}s\"];"
+                )
+            } else {
+                writeln!(
+                    dot,
+                    "    \"{from:#010x}\" -> \"{to:#010x}\" [label=\"{age}s\"];"
+                )
+            };
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}